@@ -0,0 +1,61 @@
+//! 数据文件检查工具
+//!
+//! 输出数据目录中每个 SSTable 的大小、数据条数以及压缩情况，用法：`wisc_dump [data_dir]`，
+//! 默认检查当前工作目录中配置的数据目录
+
+use anyhow::Result;
+use log::error;
+use std::env;
+use std::path::PathBuf;
+use std::process::exit;
+
+use r_wisckey::common::error_enum::WiscError;
+use r_wisckey::common::fn_util::log_init;
+use r_wisckey::config::SERVER_CONFIG;
+use r_wisckey::dump_tables;
+
+fn main() {
+    log_init();
+    if let Err(err) = run() {
+        error!("{:?}", err);
+        exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let data_dir = match env::args().nth(1) {
+        Some(dir) => PathBuf::from(dir),
+        None => env::current_dir()?.join(&SERVER_CONFIG.data_dir),
+    };
+    if !data_dir.is_dir() {
+        return Err(anyhow::Error::from(WiscError::FileNotFound(
+            data_dir.to_string_lossy().to_string(),
+        )));
+    }
+    let (mut raw_size, mut stored_size) = (0, 0);
+    for (level, number, properties) in dump_tables(&data_dir)? {
+        println!(
+            "level-{} {:>8}  size {:>10}  entries {:>8}  blocks {:>6}  raw {:>10}  stored {:>10}  ratio {:>6.2}  {:?}",
+            level,
+            number,
+            properties.file_size,
+            properties.entries,
+            properties.data_blocks,
+            properties.raw_size,
+            properties.stored_size,
+            properties.compression_ratio(),
+            properties.compressions,
+        );
+        raw_size += properties.raw_size;
+        stored_size += properties.stored_size;
+    }
+    if stored_size > 0 {
+        println!(
+            "total raw {} stored {} ratio {:.2}",
+            raw_size,
+            stored_size,
+            raw_size as f64 / stored_size as f64
+        );
+    }
+    Ok(())
+}
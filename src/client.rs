@@ -3,8 +3,14 @@
 use crate::config::SERVER_CONFIG;
 use std::io::{BufReader, BufWriter};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::Bound;
 
-use crate::client::Command::{Delete, Get, Insert, Update};
+use crate::client::Command::{
+    Batch, Begin, BlockCache, Commit, Delete, Gc, Get, Insert, Resume, Rollback, Scan, State,
+    Stats, Update,
+};
+use crate::common::types::ByteVec;
+use crate::engines::{Scans, WriteBatch};
 use anyhow::Result;
 use log::{error, info, warn};
 use rustyline::error::ReadlineError;
@@ -20,6 +26,25 @@ command parser fail, Usage:
     delete key;
     insert key value;
     update key value;
+    scan start end [limit];
+    gc;
+    batch put key value delete key ...;
+    begin; ... commit; / rollback;
+    stats;
+    block_cache capacity;
+    state;
+    resume;
+
+scan: start 前缀 '(' 表示不包含，'[' 或无前缀表示包含；
+      end 后缀 ']' 表示包含，')' 或无后缀表示不包含；
+      '*' 表示无边界，例如：scan (a z] 10;  scan * *;
+batch: 多个 put / delete 原子地写入，例如：batch put a 1 put b 2 delete c;
+begin: 开始事务，之后的 get / insert / update / delete / batch 在事务中执行，
+       commit 时读取过的 key 被修改则提交失败，rollback 放弃事务中的写入
+stats: 查看存储引擎的统计信息；
+block_cache: 调整 block 缓存的容量（字节），例如：block_cache 16777216;
+state: 查看存储引擎的状态，后台错误之后为只读（read-only）或者失败（failed），同时显示错误的原因；
+resume: 修复错误的原因（例如释放磁盘空间）之后恢复写入；
 ";
 
 /// command line 前缀
@@ -29,6 +54,20 @@ const GET: &str = "get";
 const DELETE: &str = "delete";
 const INSERT: &str = "insert";
 const UPDATE: &str = "update";
+const SCAN: &str = "scan";
+const GC: &str = "gc";
+const BATCH: &str = "batch";
+const BEGIN: &str = "begin";
+const COMMIT: &str = "commit";
+const ROLLBACK: &str = "rollback";
+const STATS: &str = "stats";
+const BLOCK_CACHE: &str = "block_cache";
+const STATE: &str = "state";
+const RESUME: &str = "resume";
+/// batch 命令中的写入操作
+const PUT: &str = "put";
+/// scan 命令中表示无边界
+const UNBOUNDED: &str = "*";
 
 /// 客户端实体
 pub struct Client {
@@ -50,6 +89,20 @@ impl Client {
         })
     }
 
+    /// 发送一条命令并等待服务端的响应
+    ///
+    /// 命令行只能输入字符串，需要读写任意字节的 key / value 时直接构造 `Command` 调用此方法
+    pub fn send(&mut self, command: &Command) -> Result<String> {
+        bincode::serialize_into::<BufWriter<&TcpStream>, Command>(
+            BufWriter::new(self.writer.get_ref()),
+            command,
+        )?;
+
+        Ok(bincode::deserialize_from::<BufReader<&TcpStream>, String>(
+            BufReader::new(self.reader.get_ref()),
+        )?)
+    }
+
     /// 启动
     pub fn run(&mut self) -> Result<()> {
         if self
@@ -67,15 +120,7 @@ impl Client {
                     self.editor.add_history_entry(line.as_str());
                     // command_parser 是否能成功转换已经在 命令行的阶段校验了
                     let command = command_parser(line.as_str()).unwrap();
-
-                    bincode::serialize_into::<BufWriter<&TcpStream>, Command>(
-                        BufWriter::new(self.writer.get_ref()),
-                        &command,
-                    )?;
-
-                    let req = bincode::deserialize_from::<BufReader<&TcpStream>, String>(
-                        BufReader::new(self.reader.get_ref()),
-                    )?;
+                    let req = self.send(&command)?;
                     println!("{}", &req);
                 }
 
@@ -111,14 +156,32 @@ pub fn command_parser(command: &str) -> Option<Command> {
         .split_whitespace()
         .map(|ele| ele.to_string())
         .collect();
+    if command_arr.first().map(String::as_str) == Some(BATCH) {
+        return batch_parser(&command_arr[1..]).map(Batch);
+    }
     return match command_arr.len() {
+        // gc
+        // begin / commit / rollback
+        // stats / state / resume
+        1 => match command_arr.first().unwrap().as_str() {
+            GC => Some(Gc),
+            STATS => Some(Stats),
+            STATE => Some(State),
+            RESUME => Some(Resume),
+            BEGIN => Some(Begin),
+            COMMIT => Some(Commit),
+            ROLLBACK => Some(Rollback),
+            _ => None,
+        },
         // get key
         // delete key
+        // block_cache capacity
         2 => {
             let key = command_arr.get(1).unwrap();
             match command_arr.get(0).unwrap().as_str() {
-                GET => Some(Get(key.to_string())),
-                DELETE => Some(Delete(key.to_string())),
+                GET => Some(Get(key.as_bytes().to_vec())),
+                DELETE => Some(Delete(key.as_bytes().to_vec())),
+                BLOCK_CACHE => key.parse::<usize>().ok().map(BlockCache),
                 _ => None,
             }
         }
@@ -128,22 +191,104 @@ pub fn command_parser(command: &str) -> Option<Command> {
             let key = command_arr.get(1).unwrap();
             let value = command_arr.get(2).unwrap();
             match command_arr.get(0).unwrap().as_str() {
-                INSERT => Some(Insert(key.to_string(), value.to_string())),
-                UPDATE => Some(Update(key.to_string(), value.to_string())),
+                INSERT => Some(Insert(key.as_bytes().to_vec(), value.as_bytes().to_vec())),
+                UPDATE => Some(Update(key.as_bytes().to_vec(), value.as_bytes().to_vec())),
+                SCAN => scan_parser(key, value, None).map(Scan),
                 _ => None,
             }
         }
+        // scan start end limit
+        4 => match command_arr.first().unwrap().as_str() {
+            SCAN => {
+                let limit = command_arr.get(3).unwrap().parse::<usize>().ok()?;
+                scan_parser(
+                    command_arr.get(1).unwrap(),
+                    command_arr.get(2).unwrap(),
+                    Some(limit),
+                )
+                .map(Scan)
+            }
+            _ => None,
+        },
         _ => None,
     };
 }
 
+/// 解析 batch 命令中的操作序列：put key value / delete key
+fn batch_parser(ops: &[String]) -> Option<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    let mut iter = ops.iter();
+    while let Some(op) = iter.next() {
+        match op.as_str() {
+            PUT => {
+                let key = iter.next()?;
+                let value = iter.next()?;
+                batch.put(key, value);
+            }
+            DELETE => {
+                batch.delete(iter.next()?);
+            }
+            _ => return None,
+        }
+    }
+    if batch.is_empty() {
+        return None;
+    }
+    Some(batch)
+}
+
+/// 解析 scan 命令的起止边界
+fn scan_parser(start: &str, end: &str, limit: Option<usize>) -> Option<Scans> {
+    let start = if start == UNBOUNDED {
+        Bound::Unbounded
+    } else if let Some(key) = start.strip_prefix('(') {
+        Bound::Excluded(key.as_bytes().to_vec())
+    } else {
+        Bound::Included(start.trim_start_matches('[').as_bytes().to_vec())
+    };
+    let end = if end == UNBOUNDED {
+        Bound::Unbounded
+    } else if let Some(key) = end.strip_suffix(']') {
+        Bound::Included(key.as_bytes().to_vec())
+    } else {
+        Bound::Excluded(end.trim_end_matches(')').as_bytes().to_vec())
+    };
+    for bound in [&start, &end] {
+        if matches!(bound, Bound::Included(key) | Bound::Excluded(key) if key.is_empty()) {
+            return None;
+        }
+    }
+    Some(Scans::new(start, end, limit))
+}
+
 /// 客户端明命令实体
+///
+/// key 和 value 都是任意的字节序列
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum Command {
-    Get(String),
-    Delete(String),
-    Insert(String, String),
-    Update(String, String),
+    Get(ByteVec),
+    Delete(ByteVec),
+    Insert(ByteVec, ByteVec),
+    Update(ByteVec, ByteVec),
+    Scan(Scans),
+    /// 手动触发 value log 垃圾回收
+    Gc,
+    /// 原子地写入一组 put / delete
+    Batch(WriteBatch),
+    /// 开始事务，同一个连接中之后的读写都在事务中执行
+    Begin,
+    /// 提交事务
+    Commit,
+    /// 放弃事务
+    Rollback,
+    /// 查看存储引擎的统计信息
+    Stats,
+    /// 调整 block 缓存的容量（字节）
+    BlockCache(usize),
+    /// 查看存储引擎的状态
+    State,
+    /// 后台错误之后恢复写入
+    Resume,
 }
 
 /// 命令行附属
@@ -9,6 +9,23 @@ pub enum WiscError {
     #[error("data corruption encountered (expected {saved_checksum:?}, got {checksum:?})")]
     DataCorruption { checksum: u32, saved_checksum: u32 },
 
+    #[error("sstable: [{0}] format invalid!")]
+    TableFormatInvalid(String),
+
+    /// block 或者 vLog entry 中记录的压缩方式无法识别
+    #[error("compression type: [{0}] invalid!")]
+    CompressionInvalid(u8),
+
+    #[error("wal: [{0}] corrupted!")]
+    WalCorrupted(String),
+
+    /// 数据目录创建时使用的比较器与打开时指定的不一致
+    #[error("comparator: [{0}] does not match [{1}]")]
+    ComparatorMismatch(String, String),
+
+    #[error("value pointer: [{0}] invalid!")]
+    ValuePointerInvalid(String),
+
     #[error("file: [{0}] not found!")]
     FileNotFound(String),
 
@@ -18,6 +35,27 @@ pub enum WiscError {
     #[error("insert fail :key: [{0}] existed, consider update that")]
     KeyExist(String),
 
+    /// 事务读取过的 key 在提交之前被其他写入修改
+    #[error("transaction conflict: key [{0}] changed since it was read")]
+    TransactionConflict(String),
+
+    #[error("no transaction in progress, consider begin one")]
+    TransactionNotStarted,
+
+    #[error("transaction already in progress, consider commit or rollback it")]
+    TransactionInProgress,
+
+    /// 后台任务或者写入失败之后切换为只读模式，修复原因之后可以 resume
+    #[error("engine is read-only: [{0}], fix the cause and resume")]
+    ReadOnly(String),
+
+    /// 数据损坏，读写都会失败，需要重新打开
+    #[error("engine failed: [{0}], reopen required")]
+    EngineFailed(String),
+
+    #[error("engine is shut down")]
+    ShutDown,
+
     #[error("SocketAddr parser fail !")]
     SocketAddrParserFail,
 }
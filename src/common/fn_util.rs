@@ -1,5 +1,4 @@
 use anyhow::Result;
-use chrono::Local;
 use crc32fast::Hasher;
 use lazy_static::lazy_static;
 use log::LevelFilter;
@@ -8,7 +7,7 @@ use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
 use std::{fs, io};
 
 use crate::common::error_enum::WiscError;
@@ -88,6 +87,12 @@ pub fn open_option_default(path: PathBuf) -> Result<File> {
         .open(path)?)
 }
 
+/// fsync 目录，确保目录中新建、重命名、删除文件的操作持久化
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
 ///  排序数据目录下的所有的数据文件，获取文件名集合
 pub fn sorted_gen_list(path: &Path, file_extension: &str, file_suffix: &str) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
@@ -115,13 +120,44 @@ pub fn get_file_path(dir: &Path, gen: i64, file_suffix: &str) -> PathBuf {
 }
 
 lazy_static! {
-    /// 全局自增元素(并非始终全局+1自增，重启后将重置基础值，从时序上来看，它是递增的)
-    pub static ref SEQUENCE:AtomicI64 = {
-        AtomicI64::new(Local::now().timestamp_millis())
-    };
+    /// 全局自增的 key 版本号，打开存储引擎时从 MANIFEST 和 WAL 中恢复，重启之后依然严格递增
+    pub static ref SEQUENCE: AtomicI64 = AtomicI64::new(1);
+    /// 全局自增的文件编号（WAL、SSTable、vLog、MANIFEST），与 sequence 相互独立，
+    /// 打开存储引擎时从 MANIFEST 以及已经存在的文件中恢复
+    pub static ref FILE_NUMBER: AtomicU64 = AtomicU64::new(1);
 }
 
 /// 获取全局增长 `i64` 序列
 pub fn gen_sequence() -> i64 {
     SEQUENCE.fetch_add(1, Ordering::SeqCst)
 }
+
+/// 一次性获取 `count` 个连续的序列，返回第一个
+pub fn gen_sequence_range(count: usize) -> i64 {
+    SEQUENCE.fetch_add(count as i64, Ordering::SeqCst)
+}
+
+/// 最近一次分配出去的序列，之后写入的数据的序列都大于它
+pub fn last_sequence() -> i64 {
+    SEQUENCE.load(Ordering::SeqCst) - 1
+}
+
+/// 保证之后生成的序列都大于 `last_sequence`
+pub fn restore_sequence(last_sequence: i64) {
+    SEQUENCE.fetch_max(last_sequence + 1, Ordering::SeqCst);
+}
+
+/// 获取新的文件编号
+pub fn gen_file_number() -> u64 {
+    FILE_NUMBER.fetch_add(1, Ordering::SeqCst)
+}
+
+/// 下一个将要分配的文件编号，不会消耗编号
+pub fn next_file_number() -> u64 {
+    FILE_NUMBER.load(Ordering::SeqCst)
+}
+
+/// 保证之后生成的文件编号都不小于 `next_file_number`
+pub fn restore_file_number(next_file_number: u64) {
+    FILE_NUMBER.fetch_max(next_file_number, Ordering::SeqCst);
+}
@@ -1,3 +1,16 @@
 //! 自定义 type
 
+use std::fmt::{Debug, Formatter};
+
 pub type ByteVec = Vec<u8>;
+
+/// 用于输出任意字节序列：合法的 UTF-8 按字符串输出，否则按转义后的字节串输出
+pub struct DisplayBytes<'a>(pub &'a [u8]);
+impl Debug for DisplayBytes<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match std::str::from_utf8(self.0) {
+            Ok(text) => write!(f, "{:?}", text),
+            Err(_) => write!(f, "b\"{}\"", self.0.escape_ascii()),
+        }
+    }
+}
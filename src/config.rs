@@ -1,5 +1,7 @@
 //! 配置文件解析
 use crate::common::error_enum::WiscError;
+use crate::engines::lsm_log_engine::compression::CompressionType;
+use crate::engines::lsm_log_engine::wal_log::{WalRecoveryMode, WalSyncMode};
 use anyhow::Result;
 use lazy_static::lazy_static;
 use serde_derive::Deserialize;
@@ -34,6 +36,42 @@ pub struct ServerConfig {
     pub wal_dir: String,
     pub log_file_suffix: String,
     pub log_file_extension: String,
+    /// value log 存储目录
+    pub vlog_dir: String,
+    pub vlog_file_suffix: String,
+    pub vlog_file_extension: String,
+    /// value 分离存储的阈值
+    pub value_threshold: usize,
+    /// vLog 垃圾回收的触发大小
+    pub vlog_gc_size_threshold: u64,
+    /// vLog 垃圾回收的垃圾比例
+    pub vlog_gc_ratio: f64,
+    /// scan 时并行读取 vLog 的线程数
+    pub scan_prefetch_threads: usize,
+    /// 重放 WAL 时对损坏 record 的处理方式
+    pub wal_recovery_mode: WalRecoveryMode,
+    /// WAL 的持久化方式
+    pub wal_sync_mode: WalSyncMode,
+    /// `interval` 持久化方式下 fsync 的间隔毫秒数
+    pub wal_sync_interval_ms: u64,
+    /// SSTable 布隆过滤器中每个 key 使用的位数，0 表示不使用过滤器
+    pub bloom_bits_per_key: usize,
+    /// 所有 SSTable 共享的 block 缓存容量（字节）
+    pub block_cache_capacity: usize,
+    /// 同时保持打开的 SSTable 个数
+    pub max_open_files: usize,
+    /// 每个层级 SSTable 的压缩方式
+    pub compression_per_level: Vec<CompressionType>,
+    /// vLog 中 value 的压缩方式
+    pub vlog_compression: CompressionType,
+    /// 内存表的内存占用达到该值（字节）时切换内存表和 log 文件
+    pub write_buffer_size: usize,
+    /// 等待 flush 的不可变内存表个数上限，达到上限时阻塞写入
+    pub max_immutable_memtables: usize,
+    /// 后台 flush 的线程数
+    pub max_background_flushes: usize,
+    /// 后台 compaction 的线程数
+    pub max_background_compactions: usize,
     // LSM 配置
     pub level_dirs: Vec<u8>,
 }
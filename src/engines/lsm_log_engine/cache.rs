@@ -0,0 +1,295 @@
+//! SSTable 读取使用的缓存
+//!
+//! - `BlockCache`：所有 SSTable 共享的 data block 缓存，按照 block 的字节数计算容量
+//! - `TableCache`：已经打开的 SSTable，保留文件句柄以及解析好的 index block 和 filter block，按照文件个数计算容量
+//!
+//! 两者都是分片的 LRU 缓存，每个分片各自加锁，减少并发读取时的锁竞争
+
+use anyhow::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::SERVER_CONFIG;
+use crate::engines::lsm_log_engine::comparator::InternalKeyComparator;
+use crate::engines::lsm_log_engine::filter::FilterStatistics;
+use crate::engines::lsm_log_engine::sstable::TableReader;
+use crate::engines::lsm_log_engine::wal_log::Key;
+
+/// 缓存的分片个数
+pub const CACHE_SHARDS: usize = 16;
+
+/// data block 缓存：(文件编号, block 偏移) -> 解码之后的 block
+///
+/// 文件编号不会重复使用，文件删除之后残留的 block 会随着 LRU 淘汰
+pub type BlockCache = ShardedLruCache<(u64, u64), Vec<Key>>;
+
+/// 缓存的统计信息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStatistics {
+    pub capacity: usize,
+    /// 当前缓存的总大小
+    pub usage: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// 单个分片，使用递增的访问序号维护 LRU 顺序
+#[derive(Debug)]
+struct LruShard<K, V> {
+    /// key -> (value, 占用的容量, 最近一次访问的序号)
+    entries: HashMap<K, (Arc<V>, usize, u64)>,
+    /// 访问序号 -> key，序号最小的即最久没有使用的
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    usage: usize,
+    capacity: usize,
+}
+impl<K: Hash + Eq + Clone, V> LruShard<K, V> {
+    fn new(capacity: usize) -> Self {
+        LruShard {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            usage: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<Arc<V>> {
+        self.tick += 1;
+        let (value, _, last_tick) = self.entries.get_mut(key)?;
+        self.order.remove(last_tick);
+        *last_tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: Arc<V>, charge: usize) {
+        self.remove(&key);
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, charge, self.tick));
+        self.usage += charge;
+        self.evict();
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((_, charge, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.usage -= charge;
+        }
+    }
+
+    /// 淘汰最久没有使用的数据，直到不超过容量
+    fn evict(&mut self) {
+        while self.usage > self.capacity {
+            match self.order.pop_first() {
+                Some((_, key)) => {
+                    if let Some((_, charge, _)) = self.entries.remove(&key) {
+                        self.usage -= charge;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// 分片的 LRU 缓存，容量平均分配到各个分片，容量为 0 时不缓存任何数据
+///
+/// 缓存中的 value 通过 `Arc` 共享，被淘汰之后正在使用它的读取不受影响
+#[derive(Debug)]
+pub struct ShardedLruCache<K, V> {
+    shards: Vec<Mutex<LruShard<K, V>>>,
+    capacity: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+impl<K: Hash + Eq + Clone, V> ShardedLruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        ShardedLruCache {
+            shards: (0..CACHE_SHARDS)
+                .map(|_| Mutex::new(LruShard::new(capacity.div_ceil(CACHE_SHARDS))))
+                .collect(),
+            capacity: AtomicUsize::new(capacity),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<LruShard<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// 查找并记录命中情况，命中时将其标记为最近使用
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        let value = self.shard(key).lock().unwrap().get(key);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// 插入 value，`charge` 为其占用的容量，超过容量时淘汰最久没有使用的数据
+    pub fn insert(&self, key: K, value: Arc<V>, charge: usize) {
+        self.shard(&key).lock().unwrap().insert(key, value, charge);
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    /// 运行时调整容量，缩小时立即淘汰超出的数据
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.capacity = capacity.div_ceil(CACHE_SHARDS);
+            shard.evict();
+        }
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        CacheStatistics {
+            capacity: self.capacity.load(Ordering::Relaxed),
+            usage: self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().usage)
+                .sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 已经打开的 SSTable 缓存，同时记录布隆过滤器的统计信息
+///
+/// 所有 version 共享同一个 `TableCache`，数据文件删除之后从缓存中移除
+#[derive(Debug)]
+pub struct TableCache {
+    tables: ShardedLruCache<u64, TableReader>,
+    block_cache: Arc<BlockCache>,
+    filter_hits: AtomicU64,
+    filter_misses: AtomicU64,
+    false_positives: AtomicU64,
+}
+impl Default for TableCache {
+    /// 按照配置文件中的容量创建
+    fn default() -> Self {
+        TableCache::new(
+            SERVER_CONFIG.max_open_files,
+            Arc::new(BlockCache::new(SERVER_CONFIG.block_cache_capacity)),
+        )
+    }
+}
+impl TableCache {
+    /// 最多缓存 `max_open_files` 个打开的文件，读取的 data block 缓存在 `block_cache` 中
+    pub fn new(max_open_files: usize, block_cache: Arc<BlockCache>) -> Self {
+        TableCache {
+            tables: ShardedLruCache::new(max_open_files),
+            block_cache,
+            filter_hits: AtomicU64::new(0),
+            filter_misses: AtomicU64::new(0),
+            false_positives: AtomicU64::new(0),
+        }
+    }
+
+    /// 获取编号为 `number` 的文件，不在缓存中时打开文件并读取 index block 和 filter block
+    pub fn table(
+        &self,
+        number: u64,
+        path: PathBuf,
+        comparator: Arc<InternalKeyComparator>,
+    ) -> Result<Arc<TableReader>> {
+        if let Some(table) = self.tables.get(&number) {
+            return Ok(table);
+        }
+        let table = Arc::new(
+            TableReader::open(path, comparator)?.with_block_cache(number, self.block_cache.clone()),
+        );
+        self.tables.insert(number, table.clone(), 1);
+        Ok(table)
+    }
+
+    /// 检查文件中是否可能存在 `user_key`，并记录过滤器的统计信息
+    pub fn may_contain(&self, table: &TableReader, user_key: &[u8]) -> bool {
+        let may_contain = table.may_contain(user_key);
+        let counter = if may_contain {
+            &self.filter_misses
+        } else {
+            &self.filter_hits
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        may_contain
+    }
+
+    /// 过滤器无法排除的文件中没有找到 key
+    pub fn record_false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 文件删除之后关闭并移除
+    pub fn evict(&self, number: u64) {
+        self.tables.remove(&number);
+    }
+
+    pub fn block_cache(&self) -> &BlockCache {
+        &self.block_cache
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        self.tables.statistics()
+    }
+
+    pub fn filter_statistics(&self) -> FilterStatistics {
+        FilterStatistics {
+            hits: self.filter_hits.load(Ordering::Relaxed),
+            misses: self.filter_misses.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lru_cache_test() {
+        let cache: ShardedLruCache<u64, u64> = ShardedLruCache::new(CACHE_SHARDS * 2);
+        // 同一个分片中的 3 个 key
+        let keys: Vec<u64> = (0..)
+            .filter(|key| std::ptr::eq(cache.shard(key), cache.shard(&0)))
+            .take(3)
+            .collect();
+        cache.insert(keys[0], Arc::new(0), 1);
+        cache.insert(keys[1], Arc::new(1), 1);
+        // 访问之后 keys[0] 成为最近使用的，插入 keys[2] 时淘汰 keys[1]
+        assert_eq!(cache.get(&keys[0]).as_deref(), Some(&0));
+        cache.insert(keys[2], Arc::new(2), 1);
+        assert!(cache.get(&keys[1]).is_none());
+        assert_eq!(cache.get(&keys[2]).as_deref(), Some(&2));
+
+        let statistics = cache.statistics();
+        assert_eq!(
+            (statistics.usage, statistics.hits, statistics.misses),
+            (2, 2, 1)
+        );
+
+        // 容量调整为 0 之后淘汰所有数据，也不再缓存新的数据
+        cache.set_capacity(0);
+        assert_eq!(cache.statistics().usage, 0);
+        cache.insert(keys[0], Arc::new(0), 1);
+        assert!(cache.get(&keys[0]).is_none());
+    }
+}
@@ -0,0 +1,344 @@
+//! major compaction，将某一层的数据文件与下一层中 key 范围重叠的文件合并
+//!
+//! 每个层级按照 `level.rs` 中的限制计算得分，得分最高且不小于 1 的层级会被压缩：
+//! 合并时每个用户 key 只保留最新的版本，下一层已经是该 key 最深的层级时删除标记也一并丢弃，
+//! 结果写入下一层的新文件，最后通过一条 `VersionEdit` 原子地提交。
+
+use anyhow::Result;
+use log::{error, info};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::common::fn_util::gen_file_number;
+use crate::common::types::ByteVec;
+use crate::engines::lsm_log_engine::comparator::InternalKeyComparator;
+use crate::engines::lsm_log_engine::compression::CompressionType;
+use crate::engines::lsm_log_engine::iterator::MergingIterator;
+use crate::engines::lsm_log_engine::level::{LevelDir, LEVEL_FILE_MAX_SIZE};
+use crate::engines::lsm_log_engine::snapshot::SnapshotList;
+use crate::engines::lsm_log_engine::sstable::TableBuilder;
+use crate::engines::lsm_log_engine::version::{FileMetaData, Version, VersionEdit, VersionSet};
+use crate::engines::lsm_log_engine::write_stall::WriteStall;
+
+/// 一次 major compaction 的输入
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compaction {
+    /// 被压缩的层级，输出写入 level + 1
+    pub level: u8,
+    /// level 中参与压缩的文件
+    pub inputs: Vec<FileMetaData>,
+    /// level + 1 中与 inputs 的 key 范围重叠的文件
+    pub next_inputs: Vec<FileMetaData>,
+}
+impl Compaction {
+    /// 选择得分最高的层级，得分都小于 1 时返回 `None`
+    ///
+    /// level-0 的文件之间 key 范围可能重叠，全部参与压缩；
+    /// 其他层级从 compact_pointer 之后的第一个文件开始，轮流压缩
+    pub fn pick(version: &Version, compact_pointers: &[ByteVec]) -> Option<Self> {
+        let (level, score) = (0..compact_pointers.len() as u8)
+            .map(|level| (level, version.compaction_score(level)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        if score < 1.0 {
+            return None;
+        }
+        let files = version.files(level);
+        let inputs = if level == 0 {
+            files.to_vec()
+        } else {
+            let pointer = &compact_pointers[level as usize];
+            let comparator = version.comparator();
+            let file = files
+                .iter()
+                .find(|file| comparator.compare_user_key(&file.largest, pointer).is_gt())
+                .unwrap_or(&files[0]);
+            vec![file.clone()]
+        };
+        let mut compaction = Compaction {
+            level,
+            inputs,
+            next_inputs: Vec::new(),
+        };
+        let comparator = version.comparator();
+        compaction.next_inputs = version.overlapping_files(
+            level + 1,
+            &compaction.smallest(&comparator),
+            &compaction.largest(&comparator),
+        );
+        Some(compaction)
+    }
+
+    /// inputs 中最小的用户 key
+    pub fn smallest(&self, comparator: &InternalKeyComparator) -> ByteVec {
+        self.inputs
+            .iter()
+            .map(|file| file.smallest.clone())
+            .min_by(|a, b| comparator.compare_user_key(a, b))
+            .unwrap_or_default()
+    }
+
+    /// inputs 中最大的用户 key
+    pub fn largest(&self, comparator: &InternalKeyComparator) -> ByteVec {
+        self.inputs
+            .iter()
+            .map(|file| file.largest.clone())
+            .max_by(|a, b| comparator.compare_user_key(a, b))
+            .unwrap_or_default()
+    }
+
+    /// 执行压缩，返回需要提交的 edit
+    ///
+    /// `snapshots` 是升序排列的存活快照。同一个用户 key 中，一个版本只对 sequence 位于
+    /// [它的 sequence, 更新的版本的 sequence) 之间的读取可见，其中没有存活快照时即可丢弃；
+    /// 删除标记只有在比所有快照都旧、并且更深的层级中没有旧版本时才丢弃，否则快照会读到被它覆盖的旧版本。
+    ///
+    /// 输出文件超过 `LEVEL_FILE_MAX_SIZE` 时，在下一个用户 key 开始时切换新的文件，
+    /// 同一个用户 key 的所有版本只会出现在一个文件中，因此输出文件之间的 key 范围不重叠
+    pub fn run(
+        &self,
+        version: &Version,
+        data_dir: &Path,
+        snapshots: &[i64],
+    ) -> Result<VersionEdit> {
+        let output_level = self.level + 1;
+        let mut sources = Vec::with_capacity(self.inputs.len() + self.next_inputs.len());
+        for file in self.inputs.iter().chain(self.next_inputs.iter()) {
+            sources.push(version.open_table(data_dir, file)?.entries()?.into_iter());
+        }
+
+        let comparator = version.comparator();
+        let mut outputs = Vec::new();
+        let mut builder: Option<(u64, TableBuilder)> = None;
+        let mut dropped = 0;
+        let mut current_user_key: Option<ByteVec> = None;
+        // 当前用户 key 中上一个（更新的）版本的 sequence
+        let mut last_sequence_for_key = i64::MAX;
+        for key in MergingIterator::all_versions(sources, comparator.clone()) {
+            let is_new_user_key = !current_user_key
+                .as_deref()
+                .is_some_and(|user_key| comparator.compare_user_key(user_key, key.key()).is_eq());
+            if is_new_user_key {
+                current_user_key = Some(key.key().to_vec());
+                last_sequence_for_key = i64::MAX;
+                if builder
+                    .as_ref()
+                    .is_some_and(|(_, table)| table.file_size() >= LEVEL_FILE_MAX_SIZE as u64)
+                {
+                    let (number, table) = builder.take().unwrap();
+                    outputs.push(FileMetaData::new(output_level, number, table.finish()?));
+                }
+            }
+            let sequence = key.sequence();
+            let visible = snapshots
+                .get(snapshots.partition_point(|snapshot| *snapshot < sequence))
+                .is_some_and(|snapshot| *snapshot < last_sequence_for_key);
+            let shadowed = last_sequence_for_key != i64::MAX && !visible;
+            last_sequence_for_key = sequence;
+            if shadowed
+                || (key.is_deleted()
+                    && snapshots
+                        .first()
+                        .is_none_or(|snapshot| sequence <= *snapshot)
+                    && !version.key_exists_below(data_dir, key.key(), output_level)?)
+            {
+                dropped += 1;
+                continue;
+            }
+            let (_, table) = match builder.as_mut() {
+                Some(table) => table,
+                None => {
+                    let number = gen_file_number();
+                    let path = LevelDir::new(data_dir, output_level).file_path(number)?;
+                    let compression = CompressionType::for_level(output_level);
+                    builder.insert((number, TableBuilder::new(path, compression)?))
+                }
+            };
+            table.add(&key)?;
+        }
+        if let Some((number, table)) = builder.take() {
+            outputs.push(FileMetaData::new(output_level, number, table.finish()?));
+        }
+
+        info!(
+            "major compaction level-{} {} 个文件 + level-{} {} 个文件 -> {} 个文件，丢弃旧版本和删除标记 {} 条",
+            self.level,
+            self.inputs.len(),
+            output_level,
+            self.next_inputs.len(),
+            outputs.len(),
+            dropped
+        );
+        let removed = self
+            .inputs
+            .iter()
+            .chain(self.next_inputs.iter())
+            .map(|file| (file.level, file.number))
+            .collect();
+        Ok(VersionEdit {
+            added: outputs,
+            removed,
+            ..VersionEdit::default()
+        })
+    }
+}
+
+/// 选择得分最高的层级执行一次 major compaction，没有需要压缩的层级或者已经有其他线程在压缩时返回 false
+///
+/// 每次压缩开始时读取存活的快照，之后创建的快照只能看到输入文件中每个用户 key 的最新版本
+pub fn compact_once(
+    versions: &Mutex<VersionSet>,
+    data_dir: &Path,
+    snapshots: &SnapshotList,
+    write_stall: &WriteStall,
+) -> Result<bool> {
+    let (version, compaction) = {
+        let mut version_set = versions.lock().unwrap();
+        match version_set.pick_compaction() {
+            Some(compaction) => (version_set.current(), compaction),
+            None => return Ok(false),
+        }
+    };
+    let result = compaction.run(&version, data_dir, &snapshots.sequences());
+    // 提交之前释放旧的 version，使输入文件可以立即被删除
+    drop(version);
+    match result {
+        Ok(edit) => {
+            versions
+                .lock()
+                .unwrap()
+                .finish_compaction(&compaction, edit)?;
+            // level-0 的文件可能减少了，唤醒被限流的写入
+            write_stall.notify();
+            Ok(true)
+        }
+        Err(err) => {
+            error!("major compaction 失败: {:?}", err);
+            versions.lock().unwrap().abort_compaction();
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engines::lsm_log_engine::comparator::MAX_SEQUENCE;
+    use crate::engines::lsm_log_engine::level::LEVEL_0_FILE_MAX_NUM;
+    use crate::engines::lsm_log_engine::wal_log::{DataType, Key};
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::sync::Arc;
+
+    fn build_table(data_dir: &Path, keys: &[Key]) -> Result<FileMetaData> {
+        let number = gen_file_number();
+        let mut builder = TableBuilder::new(
+            LevelDir::new(data_dir, 0).file_path(number)?,
+            CompressionType::for_level(0),
+        )?;
+        for key in keys {
+            builder.add(key)?;
+        }
+        Ok(FileMetaData::new(0, number, builder.finish()?))
+    }
+
+    #[test]
+    fn major_compact_test() -> Result<()> {
+        let data_dir = env::temp_dir().join("r_wisckey_compaction_test");
+        let _ = remove_dir_all(&data_dir);
+        create_dir_all(&data_dir)?;
+        let versions = Mutex::new(VersionSet::open(&data_dir, Default::default())?);
+
+        for round in 0..LEVEL_0_FILE_MAX_NUM {
+            let mut keys = vec![
+                Key::new("a", round.to_string(), DataType::Set),
+                Key::new("b", round.to_string(), DataType::Set),
+            ];
+            if round == LEVEL_0_FILE_MAX_NUM - 1 {
+                keys.push(Key::new("c", "", DataType::Delete));
+            } else {
+                keys.push(Key::new("c", round.to_string(), DataType::Set));
+            }
+            let file = build_table(&data_dir, &keys)?;
+            versions.lock().unwrap().log_and_apply(VersionEdit {
+                added: vec![file],
+                ..VersionEdit::default()
+            })?;
+        }
+        let old_files: Vec<FileMetaData> = versions.lock().unwrap().current().files(0).to_vec();
+
+        let (snapshots, write_stall) = (SnapshotList::default(), WriteStall::default());
+        while compact_once(&versions, &data_dir, &snapshots, &write_stall)? {}
+        let version = versions.lock().unwrap().current();
+        assert!(version.files(0).is_empty());
+        assert_eq!(version.files(1).len(), 1);
+        let latest = (LEVEL_0_FILE_MAX_NUM - 1).to_string();
+        assert_eq!(
+            version.get(&data_dir, b"a", MAX_SEQUENCE)?.unwrap().value(),
+            latest.as_bytes()
+        );
+        // 旧版本以及最底层的删除标记都被丢弃
+        let entries = version
+            .open_table(&data_dir, &version.files(1)[0])?
+            .entries()?;
+        assert_eq!(entries.len(), 2);
+        assert!(version.get(&data_dir, b"c", MAX_SEQUENCE)?.is_none());
+        // 没有 version 引用的输入文件已经被删除
+        for file in old_files {
+            assert!(!file.path(&data_dir)?.exists());
+        }
+
+        remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_compact_test() -> Result<()> {
+        let data_dir = env::temp_dir().join("r_wisckey_snapshot_compaction_test");
+        let _ = remove_dir_all(&data_dir);
+        create_dir_all(&data_dir)?;
+        let versions = Mutex::new(VersionSet::open(&data_dir, Default::default())?);
+        let snapshots = Arc::new(SnapshotList::default());
+
+        let mut snapshot = None;
+        for round in 0..LEVEL_0_FILE_MAX_NUM {
+            let key = if round == LEVEL_0_FILE_MAX_NUM - 1 {
+                Key::new("a", "", DataType::Delete)
+            } else {
+                Key::new("a", round.to_string(), DataType::Set)
+            };
+            let file = build_table(&data_dir, &[key])?;
+            versions.lock().unwrap().log_and_apply(VersionEdit {
+                added: vec![file],
+                ..VersionEdit::default()
+            })?;
+            // 快照只能看到第一轮写入的版本
+            if round == 0 {
+                snapshot = Some(SnapshotList::acquire(&snapshots));
+            }
+        }
+        let snapshot = snapshot.unwrap();
+
+        while compact_once(&versions, &data_dir, &snapshots, &WriteStall::default())? {}
+        let version = versions.lock().unwrap().current();
+        assert!(version.files(0).is_empty());
+        assert!(version
+            .get(&data_dir, b"a", MAX_SEQUENCE)?
+            .unwrap()
+            .is_deleted());
+        assert_eq!(
+            version
+                .get(&data_dir, b"a", snapshot.sequence())?
+                .unwrap()
+                .value(),
+            b"0"
+        );
+        // 只保留最新的删除标记和对快照可见的版本
+        let entries = version
+            .open_table(&data_dir, &version.files(1)[0])?
+            .entries()?;
+        assert_eq!(entries.len(), 2);
+
+        remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! key 的比较器以及 internal key 的编码
+//!
+//! internal key 由用户 key 和 8 字节的尾部组成：
+//!
+//! ```text
+//! | user_key | !(sequence << 8 | type) (8, big-endian) |
+//! ```
+//!
+//! 尾部取反之后按大端序存放，因此尾部按字节升序排列时 sequence 降序，
+//! 同一个用户 key 的最新版本总是排在最前面，查找最新版本只需要一次 seek
+
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::common::types::ByteVec;
+
+/// internal key 尾部的长度
+pub const INTERNAL_KEY_TRAILER_SIZE: usize = 8;
+/// sequence 占用尾部的高 56 位
+pub const MAX_SEQUENCE: i64 = (1 << 56) - 1;
+
+/// 用户 key 的排序规则
+///
+/// 同一个数据目录必须始终使用同一个比较器，`name` 会记录在 MANIFEST 中，打开时校验
+pub trait Comparator: Send + Sync + Debug {
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// 默认的比较器，按字节序比较
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BytewiseComparator;
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "wisckey.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// internal key 的比较器：用户 key 按照用户比较器升序，相同的用户 key 则 sequence 降序
+#[derive(Debug, Clone)]
+pub struct InternalKeyComparator {
+    user_comparator: Arc<dyn Comparator>,
+}
+impl InternalKeyComparator {
+    pub fn new(user_comparator: Arc<dyn Comparator>) -> Self {
+        InternalKeyComparator { user_comparator }
+    }
+
+    pub fn user_comparator(&self) -> &dyn Comparator {
+        self.user_comparator.as_ref()
+    }
+
+    /// 使用用户比较器比较两个用户 key
+    pub fn compare_user_key(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.user_comparator.compare(a, b)
+    }
+}
+impl Default for InternalKeyComparator {
+    fn default() -> Self {
+        InternalKeyComparator::new(Arc::new(BytewiseComparator))
+    }
+}
+impl Comparator for InternalKeyComparator {
+    fn name(&self) -> &str {
+        self.user_comparator.name()
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.user_comparator
+            .compare(user_key(a), user_key(b))
+            .then_with(|| trailer(a).cmp(trailer(b)))
+    }
+}
+
+/// 编码 internal key
+pub fn internal_key(user_key: &[u8], sequence: i64, data_type: u8) -> ByteVec {
+    let packed = !(((sequence as u64) << 8) | data_type as u64);
+    let mut buf = ByteVec::with_capacity(user_key.len() + INTERNAL_KEY_TRAILER_SIZE);
+    buf.extend_from_slice(user_key);
+    buf.extend_from_slice(&packed.to_be_bytes());
+    buf
+}
+
+/// 查找用户 key 在 `sequence` 时可见的版本使用的 internal key，
+/// 它排在该用户 key 所有 sequence 不大于 `sequence` 的版本之前；
+/// `sequence` 为 `MAX_SEQUENCE` 时排在所有版本之前，用于查找最新版本
+pub fn seek_key(user_key: &[u8], sequence: i64) -> ByteVec {
+    internal_key(user_key, sequence, u8::MAX)
+}
+
+/// internal key 中的用户 key
+pub fn user_key(internal_key: &[u8]) -> &[u8] {
+    &internal_key[..internal_key.len() - INTERNAL_KEY_TRAILER_SIZE]
+}
+
+/// 解析 internal key 尾部中的 (sequence, type)
+pub fn parse_trailer(internal_key: &[u8]) -> (i64, u8) {
+    let packed = !u64::from_be_bytes(trailer(internal_key).try_into().unwrap());
+    ((packed >> 8) as i64, packed as u8)
+}
+
+fn trailer(internal_key: &[u8]) -> &[u8] {
+    &internal_key[internal_key.len() - INTERNAL_KEY_TRAILER_SIZE..]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn internal_key_order_test() {
+        let comparator = InternalKeyComparator::default();
+        let mut keys = [
+            internal_key(b"a1", 1, 1),
+            internal_key(b"a", 9, 1),
+            internal_key(b"a-1", 1, 1),
+            internal_key(b"a", 10, 1),
+            internal_key(b"b", 1, 0),
+        ];
+        keys.sort_by(|a, b| comparator.compare(a, b));
+        let parsed: Vec<(&[u8], (i64, u8))> = keys
+            .iter()
+            .map(|key| (user_key(key), parse_trailer(key)))
+            .collect();
+        assert_eq!(
+            parsed,
+            vec![
+                (&b"a"[..], (10, 1)),
+                (&b"a"[..], (9, 1)),
+                (&b"a-1"[..], (1, 1)),
+                (&b"a1"[..], (1, 1)),
+                (&b"b"[..], (1, 0)),
+            ]
+        );
+        // seek key 排在该用户 key 的所有版本之前，且在更小的用户 key 之后
+        assert_eq!(
+            comparator.compare(&seek_key(b"a", MAX_SEQUENCE), &keys[0]),
+            Ordering::Less
+        );
+        assert_eq!(
+            comparator.compare(&seek_key(b"a-1", MAX_SEQUENCE), &keys[1]),
+            Ordering::Greater
+        );
+        // 按照 sequence seek 时跳过更新的版本
+        assert_eq!(
+            comparator.compare(&seek_key(b"a", 9), &keys[1]),
+            Ordering::Less
+        );
+        assert_eq!(
+            comparator.compare(&seek_key(b"a", 9), &keys[0]),
+            Ordering::Greater
+        );
+    }
+}
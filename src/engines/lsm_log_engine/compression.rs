@@ -0,0 +1,112 @@
+//! block 压缩
+//!
+//! SSTable 的每个 block、vLog 的每条 value 单独压缩，使用的压缩方式记录在 block trailer / entry header 的 1 字节中，
+//! 读取时根据该字节解压，因此同一个数据目录中可以混合存在不同压缩方式写入的文件，修改配置之后无需重写旧文件。
+//! 所有的实现都是纯 Rust：Snappy（snap）、LZ4（lz4_flex）、Zstd（ruzstd）
+
+use anyhow::Result;
+use ruzstd::decoding::StreamingDecoder;
+use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+use serde_derive::{Deserialize, Serialize};
+use std::io::Read;
+
+use crate::common::error_enum::WiscError;
+use crate::common::types::ByteVec;
+use crate::config::SERVER_CONFIG;
+
+/// 压缩方式，写入文件的是它的序号，已有的序号不能修改
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionType {
+    None = 0,
+    Snappy = 1,
+    Lz4 = 2,
+    Zstd = 3,
+}
+impl CompressionType {
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Snappy),
+            2 => Ok(CompressionType::Lz4),
+            3 => Ok(CompressionType::Zstd),
+            _ => Err(anyhow::Error::from(WiscError::CompressionInvalid(byte))),
+        }
+    }
+
+    /// 写入指定层级的 SSTable 时使用的压缩方式，配置的层级数不足时沿用最后一个
+    pub fn for_level(level: u8) -> Self {
+        let levels = &SERVER_CONFIG.compression_per_level;
+        levels
+            .get(level as usize)
+            .or(levels.last())
+            .copied()
+            .unwrap_or(CompressionType::None)
+    }
+}
+
+/// 压缩 content，返回实际使用的压缩方式以及压缩之后的内容
+///
+/// 压缩之后节省的空间不足 1/8 时，不值得读取时解压的开销，直接保存原始内容
+pub fn compress(
+    compression: CompressionType,
+    content: &[u8],
+) -> Result<(CompressionType, ByteVec)> {
+    let compressed = match compression {
+        CompressionType::None => return Ok((CompressionType::None, content.to_vec())),
+        CompressionType::Snappy => snap::raw::Encoder::new().compress_vec(content)?,
+        CompressionType::Lz4 => lz4_flex::compress_prepend_size(content),
+        CompressionType::Zstd => compress_to_vec(content, CompressionLevel::Fastest),
+    };
+    if compressed.len() >= content.len() - content.len() / 8 {
+        return Ok((CompressionType::None, content.to_vec()));
+    }
+    Ok((compression, compressed))
+}
+
+/// 按照写入时的压缩方式解压
+pub fn decompress(compression: CompressionType, content: ByteVec) -> Result<ByteVec> {
+    Ok(match compression {
+        CompressionType::None => content,
+        CompressionType::Snappy => snap::raw::Decoder::new().decompress_vec(&content)?,
+        CompressionType::Lz4 => lz4_flex::decompress_size_prepended(&content)?,
+        CompressionType::Zstd => {
+            let mut decoder = StreamingDecoder::new(content.as_slice())?;
+            let mut result = ByteVec::new();
+            decoder.read_to_end(&mut result)?;
+            result
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compress_test() -> Result<()> {
+        let content = "wisckey ".repeat(512).into_bytes();
+        for compression in [
+            CompressionType::Snappy,
+            CompressionType::Lz4,
+            CompressionType::Zstd,
+        ] {
+            let (used, compressed) = compress(compression, &content)?;
+            assert_eq!(used, compression);
+            assert!(compressed.len() < content.len() / 2);
+            let byte = used as u8;
+            assert_eq!(
+                decompress(CompressionType::from_byte(byte)?, compressed)?,
+                content
+            );
+        }
+
+        // 压缩之后没有变小的内容保存原始内容
+        assert_eq!(
+            compress(CompressionType::Zstd, b"wisc")?,
+            (CompressionType::None, b"wisc".to_vec())
+        );
+        assert!(CompressionType::from_byte(42).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! 后台错误与只读模式
+//!
+//! flush、compaction 失败，或者 WAL、vLog 的写入失败时记录一个粘滞的错误，`resume` 成功之前一直存在：
+//!
+//! - `ErrorSeverity::Hard`：磁盘空间不足等 IO 错误，存储引擎切换为只读模式，拒绝写入但依然可以读取，
+//!   修复原因之后通过 `resume` 重试失败的 flush 并恢复写入
+//! - `ErrorSeverity::Fatal`：数据损坏，读写都会失败并且不能 `resume`，需要检查数据之后重新打开
+//!
+//! 同时出现多个错误时保留最严重的一个，严重程度相同时保留最早的
+
+use anyhow::Result;
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::common::error_enum::WiscError;
+use crate::engines::lsm_log_engine::write_stall::WriteStall;
+
+/// 错误的严重程度
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorSeverity {
+    /// 只读，修复原因之后可以 resume
+    Hard,
+    /// 读写都会失败，不能 resume
+    Fatal,
+}
+impl ErrorSeverity {
+    /// 根据错误链中的原因判断：数据损坏为 `Fatal`，其他（包括磁盘空间不足）为 `Hard`
+    pub fn of(err: &anyhow::Error) -> Self {
+        let corrupted = err.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<WiscError>(),
+                Some(
+                    WiscError::DataCorruption { .. }
+                        | WiscError::TableFormatInvalid(_)
+                        | WiscError::CompressionInvalid(_)
+                        | WiscError::WalCorrupted(_)
+                        | WiscError::ValuePointerInvalid(_)
+                )
+            )
+        });
+        if corrupted {
+            ErrorSeverity::Fatal
+        } else {
+            ErrorSeverity::Hard
+        }
+    }
+}
+
+/// 出错的操作
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorSource {
+    Flush,
+    Compaction,
+    /// 前台写入 WAL 或者 vLog
+    Write,
+    /// interval 持久化方式下后台的 fsync
+    WalSync,
+}
+
+/// 记录下来的错误
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackgroundError {
+    pub severity: ErrorSeverity,
+    pub source: ErrorSource,
+    pub message: String,
+    /// 磁盘空间不足，释放空间之后即可 resume
+    pub no_space: bool,
+}
+impl fmt::Display for BackgroundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} error in {:?}{}: {}",
+            self.severity,
+            self.source,
+            if self.no_space { " (no space)" } else { "" },
+            self.message
+        )
+    }
+}
+
+/// 存储引擎的状态
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum EngineState {
+    /// 正常读写
+    Normal,
+    /// 拒绝写入，读取不受影响
+    ReadOnly(BackgroundError),
+    /// 读写都会失败
+    Failed(BackgroundError),
+    /// 已经关闭，拒绝写入
+    ShutDown,
+}
+impl fmt::Display for EngineState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineState::Normal => write!(f, "normal"),
+            EngineState::ReadOnly(err) => write!(f, "read-only, {}; fix the cause and resume", err),
+            EngineState::Failed(err) => write!(f, "failed, {}; reopen required", err),
+            EngineState::ShutDown => write!(f, "shut down"),
+        }
+    }
+}
+
+/// 存储引擎、后台线程共享的错误状态
+#[derive(Debug, Default)]
+pub struct ErrorHandler {
+    error: Mutex<Option<BackgroundError>>,
+    /// 出错之后唤醒阻塞的写入，resume 之后恢复限流
+    write_stall: Arc<WriteStall>,
+}
+impl ErrorHandler {
+    pub fn new(write_stall: Arc<WriteStall>) -> Self {
+        ErrorHandler {
+            error: Mutex::new(None),
+            write_stall,
+        }
+    }
+
+    /// 记录错误并停止写入，返回该错误的严重程度
+    pub fn record(&self, source: ErrorSource, err: &anyhow::Error) -> ErrorSeverity {
+        let severity = ErrorSeverity::of(err);
+        let recorded = BackgroundError {
+            severity,
+            source,
+            message: format!("{:#}", err),
+            no_space: err.chain().any(|cause| {
+                cause.downcast_ref::<io::Error>().is_some_and(|err| {
+                    matches!(
+                        err.kind(),
+                        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+                    )
+                })
+            }),
+        };
+        error!("后台错误，停止写入: {}", recorded);
+        {
+            let mut error = self.error.lock().unwrap();
+            if error
+                .as_ref()
+                .is_none_or(|existing| existing.severity < severity)
+            {
+                *error = Some(recorded);
+            }
+        }
+        // 错误记录之后再唤醒，被唤醒的写入一定能看到该错误
+        self.write_stall.stop();
+        severity
+    }
+
+    pub fn error(&self) -> Option<BackgroundError> {
+        self.error.lock().unwrap().clone()
+    }
+
+    /// 存在任何错误时拒绝写入
+    pub fn check_writable(&self) -> Result<()> {
+        match self.error() {
+            None => Ok(()),
+            Some(err) if err.severity == ErrorSeverity::Fatal => Err(anyhow::Error::from(
+                WiscError::EngineFailed(err.to_string()),
+            )),
+            Some(err) => Err(anyhow::Error::from(WiscError::ReadOnly(err.to_string()))),
+        }
+    }
+
+    /// 存在 `Fatal` 错误时拒绝读取
+    pub fn check_readable(&self) -> Result<()> {
+        match self.error() {
+            Some(err) if err.severity == ErrorSeverity::Fatal => Err(anyhow::Error::from(
+                WiscError::EngineFailed(err.to_string()),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// resume 成功之后清除错误，恢复写入
+    pub fn clear(&self) {
+        *self.error.lock().unwrap() = None;
+        self.write_stall.resume();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_test() {
+        let write_stall = Arc::new(WriteStall::default());
+        let handler = ErrorHandler::new(write_stall.clone());
+        assert!(handler.check_writable().is_ok());
+
+        let no_space = anyhow::Error::from(io::Error::from(io::ErrorKind::StorageFull));
+        assert_eq!(
+            handler.record(
+                ErrorSource::Flush,
+                &no_space.context("写入 level-0 文件失败")
+            ),
+            ErrorSeverity::Hard
+        );
+        let err = handler.error().unwrap();
+        assert!(err.no_space);
+        assert!(write_stall.is_stopped());
+        // 只读模式下依然可以读取
+        assert!(matches!(
+            handler
+                .check_writable()
+                .unwrap_err()
+                .downcast_ref::<WiscError>(),
+            Some(WiscError::ReadOnly(_))
+        ));
+        assert!(handler.check_readable().is_ok());
+
+        // 更严重的错误覆盖之前的错误，之后的 Hard 错误不会覆盖它
+        let corrupted = anyhow::Error::from(WiscError::WalCorrupted("1.xlog".to_string()));
+        handler.record(ErrorSource::Compaction, &corrupted);
+        handler.record(ErrorSource::Write, &anyhow::anyhow!("io"));
+        let err = handler.error().unwrap();
+        assert_eq!(
+            (err.severity, err.source),
+            (ErrorSeverity::Fatal, ErrorSource::Compaction)
+        );
+        assert!(handler.check_readable().is_err());
+
+        handler.clear();
+        assert!(handler.check_writable().is_ok());
+        assert!(!write_stall.is_stopped());
+    }
+}
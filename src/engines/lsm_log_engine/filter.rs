@@ -0,0 +1,154 @@
+//! SSTable 的布隆过滤器
+//!
+//! 每个 SSTable 包含一个 filter block，覆盖文件中所有的用户 key。
+//! 编码格式与 LevelDB 相同：位数组之后追加 1 字节的哈希函数个数 k，使用双重哈希生成 k 个位置。
+//! 点查询先检查过滤器，过滤器确定 key 不存在时不需要读取文件中的任何 data block
+
+use crate::common::types::ByteVec;
+
+/// 哈希函数个数的上限，超过时视为无法识别的过滤器
+const MAX_PROBES: u8 = 30;
+
+/// 布隆过滤器使用的哈希函数（LevelDB 的 Hash，seed 固定）
+pub fn bloom_hash(key: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+    let mut h = SEED ^ (key.len() as u32).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h.wrapping_add(u32::from_le_bytes(chunk.try_into().unwrap()));
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((*byte as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+/// 收集 SSTable 中的用户 key，写入文件时生成 filter block
+#[derive(Debug)]
+pub struct FilterBuilder {
+    bits_per_key: usize,
+    hashes: Vec<u32>,
+    /// 同一个用户 key 的多个版本相邻，只需要添加一次
+    last_key: Option<ByteVec>,
+}
+impl FilterBuilder {
+    /// `bits_per_key` 为 0 时不生成过滤器，filter block 为空
+    pub fn new(bits_per_key: usize) -> Self {
+        FilterBuilder {
+            bits_per_key,
+            hashes: Vec::new(),
+            last_key: None,
+        }
+    }
+
+    pub fn add(&mut self, user_key: &[u8]) {
+        if self.bits_per_key == 0 || self.last_key.as_deref() == Some(user_key) {
+            return;
+        }
+        self.hashes.push(bloom_hash(user_key));
+        self.last_key = Some(user_key.to_vec());
+    }
+
+    /// 生成 filter block 的内容
+    pub fn finish(self) -> ByteVec {
+        if self.bits_per_key == 0 {
+            return ByteVec::new();
+        }
+        // 位数过少时误判率很高，至少使用 64 位
+        let bits = (self.hashes.len() * self.bits_per_key).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+        // k = ln2 * bits_per_key 时误判率最低
+        let probes = ((self.bits_per_key as f64 * 0.69) as u8).clamp(1, MAX_PROBES);
+        let mut content = vec![0_u8; bytes + 1];
+        content[bytes] = probes;
+        for hash in self.hashes {
+            for bit in probe_positions(hash, probes, bits) {
+                content[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        content
+    }
+}
+
+/// 从 filter block 中读取的过滤器
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    content: ByteVec,
+}
+impl BloomFilter {
+    pub fn new(content: ByteVec) -> Self {
+        BloomFilter { content }
+    }
+
+    /// 返回 false 时 key 一定不在文件中；没有过滤器或者无法识别时总是返回 true
+    pub fn may_contain(&self, user_key: &[u8]) -> bool {
+        if self.content.len() < 2 {
+            return true;
+        }
+        let bytes = self.content.len() - 1;
+        let probes = self.content[bytes];
+        if probes > MAX_PROBES {
+            return true;
+        }
+        probe_positions(bloom_hash(user_key), probes, bytes * 8)
+            .all(|bit| self.content[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// 双重哈希：每次在 hash 的基础上加上 hash 循环右移 17 位的值
+fn probe_positions(hash: u32, probes: u8, bits: usize) -> impl Iterator<Item = usize> {
+    let delta = hash.rotate_right(17);
+    (0..probes as u32).map(move |i| hash.wrapping_add(delta.wrapping_mul(i)) as usize % bits)
+}
+
+/// 过滤器的统计信息，由 `TableCache` 记录
+///
+/// - hits：过滤器确定 key 不存在，跳过了整个文件
+/// - misses：过滤器无法排除，需要读取文件
+/// - false_positives：需要读取文件，但是文件中并没有该 key（只统计读取最新版本的查询，
+///   通过快照读取时文件中可能只有更新的版本，无法区分）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterStatistics {
+    pub hits: u64,
+    pub misses: u64,
+    pub false_positives: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bloom_filter_test() {
+        let mut builder = FilterBuilder::new(10);
+        for i in 0..10000 {
+            builder.add(format!("key_{}", i).as_bytes());
+        }
+        let filter = BloomFilter::new(builder.finish());
+        for i in 0..10000 {
+            assert!(filter.may_contain(format!("key_{}", i).as_bytes()));
+        }
+        // 每个 key 10 位时误判率约为 1%
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("missing_{}", i).as_bytes()))
+            .count();
+        assert!(
+            false_positives < 300,
+            "false positives: {}",
+            false_positives
+        );
+
+        // 不生成过滤器时总是需要读取文件
+        let empty = BloomFilter::new(FilterBuilder::new(0).finish());
+        assert!(empty.may_contain(b"anything"));
+    }
+}
@@ -0,0 +1,177 @@
+//! 多路归并迭代器
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+use crate::common::types::ByteVec;
+use crate::engines::lsm_log_engine::comparator::{Comparator, InternalKeyComparator};
+use crate::engines::lsm_log_engine::wal_log::Key;
+
+/// 堆中的元素，记录 key 以及它来自哪一路迭代器
+struct HeapItem {
+    key: Key,
+    internal_key: ByteVec,
+    source: usize,
+    comparator: Arc<InternalKeyComparator>,
+}
+impl HeapItem {
+    fn new(key: Key, source: usize, comparator: Arc<InternalKeyComparator>) -> Self {
+        HeapItem {
+            internal_key: key.internal_key(),
+            key,
+            source,
+            comparator,
+        }
+    }
+
+    /// 用户 key 升序，同一个用户 key 则 sequence 降序（新版本在前）
+    ///
+    /// vLog 垃圾回收搬迁的指针保留原来的 sequence，internal key 完全相同时排在前面的迭代器（更新的数据）在前
+    fn order(&self, other: &Self) -> Ordering {
+        self.comparator
+            .compare(&self.internal_key, &other.internal_key)
+            .then_with(|| self.source.cmp(&other.source))
+    }
+}
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.order(other) == Ordering::Equal
+    }
+}
+impl Eq for HeapItem {}
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for HeapItem {
+    /// `BinaryHeap` 是大顶堆，这里反转顺序使最小的元素位于堆顶
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order(other).reverse()
+    }
+}
+
+/// 将多路按照 internal key 有序的迭代器归并为一路，迭代器按照数据从新到旧的顺序传入
+///
+/// 默认同一个用户 key 只返回 sequence 最大的版本（包括删除标记，是否过滤由调用方决定）
+pub struct MergingIterator<I: Iterator<Item = Key>> {
+    sources: Vec<I>,
+    heap: BinaryHeap<HeapItem>,
+    comparator: Arc<InternalKeyComparator>,
+    /// 是否返回同一个用户 key 的所有版本
+    all_versions: bool,
+}
+impl<I: Iterator<Item = Key>> MergingIterator<I> {
+    pub fn new(sources: Vec<I>, comparator: Arc<InternalKeyComparator>) -> Self {
+        MergingIterator::with_versions(sources, comparator, false)
+    }
+
+    /// 按照 internal key 的顺序返回所有版本，compaction 需要据此判断哪些旧版本仍然对快照可见
+    pub fn all_versions(sources: Vec<I>, comparator: Arc<InternalKeyComparator>) -> Self {
+        MergingIterator::with_versions(sources, comparator, true)
+    }
+
+    fn with_versions(
+        mut sources: Vec<I>,
+        comparator: Arc<InternalKeyComparator>,
+        all_versions: bool,
+    ) -> Self {
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        for (source, iter) in sources.iter_mut().enumerate() {
+            if let Some(key) = iter.next() {
+                heap.push(HeapItem::new(key, source, comparator.clone()));
+            }
+        }
+        MergingIterator {
+            sources,
+            heap,
+            comparator,
+            all_versions,
+        }
+    }
+
+    /// 从堆中弹出一个元素，并用同一路迭代器的下一个元素补充
+    fn pop(&mut self) -> Option<Key> {
+        let item = self.heap.pop()?;
+        if let Some(key) = self.sources[item.source].next() {
+            self.heap
+                .push(HeapItem::new(key, item.source, self.comparator.clone()));
+        }
+        Some(item.key)
+    }
+}
+impl<I: Iterator<Item = Key>> Iterator for MergingIterator<I> {
+    type Item = Key;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let newest = self.pop()?;
+        if self.all_versions {
+            return Some(newest);
+        }
+        // 跳过同一个用户 key 的旧版本
+        while let Some(item) = self.heap.peek() {
+            if self
+                .comparator
+                .compare_user_key(item.key.key(), newest.key())
+                != Ordering::Equal
+            {
+                break;
+            }
+            self.pop();
+        }
+        Some(newest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::types::ByteVec;
+    use crate::engines::lsm_log_engine::wal_log::DataType;
+
+    fn key(key: &str, value: &str) -> Key {
+        Key::new(key, value, DataType::Set)
+    }
+
+    #[test]
+    fn merge_test() {
+        let old = vec![key("a", "1"), key("b", "1"), key("d", "1")];
+        let new = vec![key("b", "2"), key("c", "2")];
+        let versions: Vec<ByteVec> = MergingIterator::all_versions(
+            vec![new.clone().into_iter(), old.clone().into_iter()],
+            Arc::default(),
+        )
+        .map(|ele| ele.value().to_vec())
+        .collect();
+        assert_eq!(versions, [b"1", b"2", b"1", b"2", b"1"]);
+
+        let merged: Vec<(ByteVec, ByteVec)> =
+            MergingIterator::new(vec![new.into_iter(), old.into_iter()], Arc::default())
+                .map(|ele| (ele.key().to_vec(), ele.value().to_vec()))
+                .collect();
+        assert_eq!(
+            merged,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"2".to_vec()),
+                (b"d".to_vec(), b"1".to_vec()),
+            ]
+        );
+
+        // sequence 相同时更新的迭代器优先
+        let relocated = vec![Key::with_sequence("a", "new", DataType::ValuePointer, 1)];
+        let original = vec![Key::with_sequence("a", "old", DataType::ValuePointer, 1)];
+        for all_versions in [false, true] {
+            let first = MergingIterator::with_versions(
+                vec![relocated.clone().into_iter(), original.clone().into_iter()],
+                Arc::default(),
+                all_versions,
+            )
+            .next()
+            .unwrap();
+            assert_eq!(first.value(), b"new");
+        }
+    }
+}
@@ -3,18 +3,20 @@
 #![allow(dead_code)]
 
 use anyhow::Result;
-use std::env;
-use std::fs::{create_dir_all, read_dir, File, OpenOptions};
-use std::io::BufWriter;
-use std::path::PathBuf;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
 
-use crate::common::fn_util::{gen_sequence, get_file_path, open_option_default};
+use crate::common::fn_util::{get_file_path, sorted_gen_list};
 use crate::config::SERVER_CONFIG;
 
 /// LEVEL_0 单个文件的大小 1M
 pub const LEVEL_0_FILE_MAX_SIZE: u64 = 1024 * 1024;
 /// LEVEL_0 层所有文件的最大数量
 pub const LEVEL_0_FILE_MAX_NUM: usize = 4;
+/// LEVEL_0 的文件数达到该值时说明 compaction 跟不上写入，每次写入都会被延迟
+pub const LEVEL_0_SLOWDOWN_TRIGGER: usize = LEVEL_0_FILE_MAX_NUM * 2;
+/// LEVEL_0 的文件数达到该值时停止写入，直到 compaction 完成
+pub const LEVEL_0_STOP_TRIGGER: usize = LEVEL_0_FILE_MAX_NUM * 3;
 
 /// 非 LEVEL_0 单个文件的大小 2M
 pub const LEVEL_FILE_MAX_SIZE: usize = 1024 * 1024 * 2;
@@ -23,81 +25,70 @@ pub const LEVEL_FILE_BASE_MAX_NUM: usize = 4;
 /// 基于第一层 后续层级的 最大总容量增长因子（level-2：10^2 = 100M, level-3：10^3 = 1000M....）
 pub const LEVEL_FILE_BASE_GROW_FACTOR: usize = 10;
 
+/// 非 LEVEL_0 层级所有文件的最大总大小，超过之后需要向下一层压缩
+pub fn level_max_bytes(level: u8) -> u64 {
+    let base = (LEVEL_FILE_MAX_SIZE * LEVEL_FILE_BASE_MAX_NUM) as u64;
+    base * (LEVEL_FILE_BASE_GROW_FACTOR as u64).pow(level.saturating_sub(1) as u32)
+}
+
+/// 层级目录名前缀
+pub const LEVEL_DIR_PREFIX: &str = "level_";
+
 /// LevelDir抽象
-pub struct LevelDir(String, u8);
+pub struct LevelDir {
+    /// 存放数据文件的基础目录
+    data_dir: PathBuf,
+    level: u8,
+}
 impl LevelDir {
-    pub fn new(level_num: u8) -> Self {
+    pub fn new(data_dir: &Path, level_num: u8) -> Self {
         LevelDir {
-            0: "level_".to_string(),
-            1: level_num,
+            data_dir: data_dir.to_path_buf(),
+            level: level_num,
         }
     }
 
     /// 将 `LevelDir` 装换为 `PathBuf`
     pub fn to_path(&self) -> Result<PathBuf> {
-        let data_dir = env::current_dir()?.join(&SERVER_CONFIG.data_dir);
-        let level_dir = data_dir.join(format!("{}{}", self.0, self.1));
+        let level_dir = self
+            .data_dir
+            .join(format!("{}{}", LEVEL_DIR_PREFIX, self.level));
         create_dir_all(&level_dir)?;
         Ok(level_dir)
     }
 
-    /// 初始化 创建并返回当前 `LevelDir` 的 writer
-    pub fn init_level_0_writer(&self) -> Result<BufWriter<File>> {
-        let path = self.to_path()?;
-        let read_dir_count = read_dir(&path)?;
-
-        let file_count = read_dir_count.count();
-        let new_file = || {
-            open_option_default(get_file_path(
-                &path,
-                gen_sequence(),
-                SERVER_CONFIG.data_file_suffix.as_str(),
-            ))
-        };
-        // 没有任何文件
-        if file_count == 0 {
-            return Ok(BufWriter::new(new_file()?));
-        }
-        let read_dir = read_dir(&path).unwrap();
-        // 已经存在文件
-        // 最后一个文件
-        let last_file = read_dir.last().unwrap().unwrap();
-        if last_file.metadata()?.len() <= LEVEL_0_FILE_MAX_SIZE {
-            return Ok(BufWriter::new(
-                OpenOptions::new()
-                    .append(true)
-                    .read(true)
-                    .write(true)
-                    .open(last_file.path())?,
-            ));
-        }
-        // 如果文件总数未满
-        if file_count < LEVEL_0_FILE_MAX_NUM {
-            return Ok(BufWriter::new(new_file()?));
-        }
-        // 文件总数已满
-        else {
-            // 到这里的条件已经是 当前的文件总数 == LEVEL_0_FILE_MAX_NUM
-            // 并且 最后一个文件不够存放数据了，需要执行major 压缩
-            // TODO major;并不是major阻塞，是当前线程主动根据状态阻塞，防止level-0文件数量超过4
+    /// 当前层级中指定编号的数据文件 path
+    pub fn file_path(&self, number: u64) -> Result<PathBuf> {
+        Ok(get_file_path(
+            &self.to_path()?,
+            number as i64,
+            SERVER_CONFIG.data_file_suffix.as_str(),
+        ))
+    }
 
-            loop {
-                if file_count < LEVEL_0_FILE_MAX_NUM {
-                    break;
-                }
-            }
-        }
-        // 返回新的
-        Ok(BufWriter::new(new_file()?))
+    /// 当前层级目录中所有的数据文件（编号, path），按照编号升序排列
+    ///
+    /// 直接读取目录，不区分文件是否属于当前 version，只用于 dump 等检查工具
+    pub fn table_files(&self) -> Result<Vec<(u64, PathBuf)>> {
+        sorted_gen_list(
+            &self.to_path()?,
+            SERVER_CONFIG.data_file_extension.as_str(),
+            SERVER_CONFIG.data_file_suffix.as_str(),
+        )?
+        .into_iter()
+        .map(|number| Ok((number, self.file_path(number)?)))
+        .collect()
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::env;
     #[test]
     fn test() {
-        let level = LevelDir::new(0);
+        let data_dir = env::current_dir().unwrap().join(&SERVER_CONFIG.data_dir);
+        let level = LevelDir::new(&data_dir, 0);
         level.to_path().unwrap();
     }
 }
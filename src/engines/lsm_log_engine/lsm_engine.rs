@@ -3,128 +3,1680 @@
 #![allow(dead_code)]
 
 use anyhow::Result;
-use crossbeam_skiplist::SkipMap;
-use log::info;
-use std::fs::{File, OpenOptions, remove_file};
-use std::io::{BufWriter, Write};
-use std::path::{PathBuf};
-use std::sync::{Arc, Mutex};
+use log::{error, info, warn};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::env;
+use std::fs::{create_dir_all, remove_file, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::thread;
+use std::time::Duration;
 
-use crate::engines::lsm_log_engine::level::LevelDir;
-use crate::engines::lsm_log_engine::mem::MemTables;
-use crate::engines::lsm_log_engine::wal_log::{DataType, Key, LogRecordRead, LogRecordWrite};
-use crate::engines::Scans;
+use crate::common::error_enum::WiscError;
+use crate::common::fn_util::{
+    gen_file_number, gen_sequence_range, restore_file_number, restore_sequence,
+};
+use crate::common::types::ByteVec;
+use crate::config::SERVER_CONFIG;
+use crate::engines::lsm_log_engine::cache::CacheStatistics;
+use crate::engines::lsm_log_engine::compaction::compact_once;
+use crate::engines::lsm_log_engine::comparator::{
+    BytewiseComparator, Comparator, InternalKeyComparator, MAX_SEQUENCE,
+};
+use crate::engines::lsm_log_engine::compression::CompressionType;
+use crate::engines::lsm_log_engine::error_handler::{
+    EngineState, ErrorHandler, ErrorSeverity, ErrorSource,
+};
+use crate::engines::lsm_log_engine::filter::FilterStatistics;
+use crate::engines::lsm_log_engine::iterator::MergingIterator;
+use crate::engines::lsm_log_engine::level::{
+    LevelDir, LEVEL_0_SLOWDOWN_TRIGGER, LEVEL_0_STOP_TRIGGER,
+};
+use crate::engines::lsm_log_engine::mem::{ImmutableQueue, MemTable, MemTables};
+use crate::engines::lsm_log_engine::scheduler::{
+    BackgroundScheduler, JobContext, JobKind, JobPriority, SchedulerStatistics, ShutdownMode,
+};
+use crate::engines::lsm_log_engine::snapshot::{Snapshot, SnapshotList};
+use crate::engines::lsm_log_engine::sstable::TableBuilder;
+use crate::engines::lsm_log_engine::version::{FileMetaData, Version, VersionEdit, VersionSet};
+use crate::engines::lsm_log_engine::vlog::{ValueLog, ValuePointer, VlogGcReport};
+use crate::engines::lsm_log_engine::wal_log::{
+    log_files, log_number, DataType, DroppedRange, GroupCommit, Key, LogRecordRead, LogRecordWrite,
+    WalRecoveryMode, WalSyncMode,
+};
+use crate::engines::lsm_log_engine::write_stall::{StallCause, StallStatistics, WriteStall};
+use crate::engines::{BatchOp, Scans, WriteBatch};
 use crate::KvsEngine;
 
-/// minor-thread name
-pub const MINOR_THREAD: &str = "minor-thread";
+/// interval 持久化方式下后台 fsync 的线程名
+pub const WAL_SYNC_THREAD: &str = "wal-sync-thread";
+/// scan 时并行读取 vLog value 的线程名前缀
+pub const PREFETCH_THREAD: &str = "prefetch-thread";
+
+/// 存储引擎的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineStatistics {
+    /// data block 缓存，容量为字节数
+    pub block_cache: CacheStatistics,
+    /// 已打开的 SSTable 缓存，容量为文件个数
+    pub table_cache: CacheStatistics,
+    /// 点查询中 SSTable 布隆过滤器的效果
+    pub filter: FilterStatistics,
+    /// 写入被限流的次数和时长
+    pub write_stall: StallStatistics,
+    /// 后台 flush 和 compaction 任务
+    pub background: SchedulerStatistics,
+    /// group_commit 方式下 WAL 实际执行 fsync 的次数
+    pub wal_syncs: u64,
+}
 
 /// 更新操作最终在lsm看来只有两种操作：set和 delete
 ///
 /// 在执行用户的 update操作之前需要先执行get操作。
-/// 存在则执行，不存在则返回用户执行insert操作
+/// 存在则执行，不存在则返回用户执行insert操作。
+///
+/// `LsmLogEngine::write` 只需要共享引用，多个线程可以同时写入；读取同样只需要共享引用
 #[derive(Debug)]
 pub struct LsmLogEngine {
     /// 接收用户的命令之后需要写 WAL日志，因此
-    wal_writer: LogRecordWrite,
-    /// 故障恢复时需要读取 WAL日志
-    wal_reader: LogRecordRead,
+    ///
+    /// 同时作为写锁：分配 sequence、追加 WAL、插入内存表以及切换内存表都在持有它时进行
+    wal_writer: Arc<Mutex<LogRecordWrite>>,
+    /// WAL 所在的目录
+    wal_dir: PathBuf,
+    /// group_commit 方式下释放写锁之后等待 fsync
+    group_commit: Arc<GroupCommit>,
+    /// 当前 log 文件的 fsync 句柄，切换文件之后同样指向新的文件
+    wal_sync_file: Arc<Mutex<File>>,
+    /// 启动时重放 WAL 丢弃的字节范围
+    wal_dropped: Vec<DroppedRange>,
     /// MemTable,因为我们需要保持数据的有序性，
-    mem_tables: MemTables,
     /// mem_table 不可变之后将刷入 level-0 SSTable
-    level_0_writer: BufWriter<File>,
+    ///
+    /// 插入只需要读锁，只有切换内存表时需要写锁
+    mem_tables: Arc<RwLock<MemTables>>,
+    /// 超过阈值的 value 存放在 vLog 中，LSM 中只保存指针
+    vlog: Arc<RwLock<ValueLog>>,
+    /// vLog 垃圾回收期间持有，同一时刻只回收一个文件
+    vlog_gc_lock: Arc<Mutex<()>>,
+    /// 存放数据文件的基础目录
+    data_dir: PathBuf,
+    /// 每个层级中存活的数据文件，由 MANIFEST 持久化
+    versions: Arc<Mutex<VersionSet>>,
+    /// scan 时并行读取 vLog value 的线程池
+    prefetch_pool: ThreadPool,
+    /// internal key 比较器，memtable、SSTable 和归并迭代器共用
+    comparator: Arc<InternalKeyComparator>,
+    /// 存活的快照，compaction 会保留对它们可见的版本
+    snapshots: Arc<SnapshotList>,
+    /// flush 和 compaction 跟不上写入时限制写入
+    write_stall: Arc<WriteStall>,
+    /// 执行 flush 和 compaction 的后台线程池
+    scheduler: BackgroundScheduler,
+    /// 后台任务或者写入失败之后记录的错误，存在时拒绝写入
+    errors: Arc<ErrorHandler>,
 }
 impl LsmLogEngine {
+    /// 在当前工作目录中打开存储引擎
     pub fn open() -> Result<Self> {
-        // 初始化 wal_writer 和 wal_reader
-        let wal_writer = LogRecordWrite::new()?;
-        let wal_reader = LogRecordRead::new()?;
-        // 初始化 mem_table
-        let mem_tables = MemTables::new();
-
-        // 初始化 sst_writer，从level_0文件夹中查找，因为 minor compression 直接
-        // 刷到level_0层级中的sst文件中
-        let level_0_writer = LevelDir::new(0).init_level_0_writer()?;
-
-        Ok(LsmLogEngine {
-            wal_writer,
-            wal_reader,
-            mem_tables,
-            level_0_writer,
+        LsmLogEngine::open_at(&env::current_dir()?)
+    }
+
+    /// 在指定的根目录中打开存储引擎，数据和 WAL 目录按照配置文件位于其中
+    pub fn open_at(root: &Path) -> Result<Self> {
+        LsmLogEngine::open_with_comparator(root, Arc::new(BytewiseComparator))
+    }
+
+    /// 使用自定义的用户 key 比较器打开存储引擎
+    ///
+    /// 同一个数据目录必须始终使用同一个比较器
+    pub fn open_with_comparator(root: &Path, user_comparator: Arc<dyn Comparator>) -> Result<Self> {
+        let comparator = Arc::new(InternalKeyComparator::new(user_comparator));
+        let data_dir = root.join(&SERVER_CONFIG.data_dir);
+        let wal_dir = root.join(&SERVER_CONFIG.wal_dir);
+        create_dir_all(&data_dir)?;
+        let versions = Arc::new(Mutex::new(VersionSet::open(&data_dir, comparator.clone())?));
+        // 初始化 mem_table，并从尚未持久化的 log 文件中恢复数据，恢复完成之前不接受写入
+        let mut mem_tables = MemTables::new(comparator.clone());
+        let wal_dropped = recover(&mut mem_tables, &versions, &wal_dir, &data_dir)?;
+        // 恢复之后再创建新的 log 文件，它的编号大于所有被恢复的 log 文件
+        let wal_writer = LogRecordWrite::new(&wal_dir)?;
+        let vlog = ValueLog::open(&data_dir)?;
+        let write_stall = Arc::new(WriteStall::default());
+        let errors = Arc::new(ErrorHandler::new(write_stall.clone()));
+        if SERVER_CONFIG.wal_sync_mode == WalSyncMode::Interval {
+            interval_sync(
+                Arc::downgrade(&wal_writer.sync_handle()),
+                Arc::downgrade(&vlog.sync_handle()),
+                errors.clone(),
+                Duration::from_millis(SERVER_CONFIG.wal_sync_interval_ms),
+            )?;
+        }
+        let prefetch_threads = match SERVER_CONFIG.scan_prefetch_threads {
+            0 => num_cpus::get(),
+            threads => threads,
+        };
+        let prefetch_pool = ThreadPoolBuilder::new()
+            .num_threads(prefetch_threads)
+            .thread_name(|index| format!("{}-{}", PREFETCH_THREAD, index))
+            .build()?;
+
+        let scheduler = BackgroundScheduler::new(
+            SERVER_CONFIG.max_background_flushes,
+            SERVER_CONFIG.max_background_compactions,
+            errors.clone(),
+        )?;
+
+        let engine = LsmLogEngine {
+            group_commit: wal_writer.group_commit(),
+            wal_sync_file: wal_writer.sync_handle(),
+            wal_writer: Arc::new(Mutex::new(wal_writer)),
+            wal_dir,
+            wal_dropped,
+            mem_tables: Arc::new(RwLock::new(mem_tables)),
+            vlog: Arc::new(RwLock::new(vlog)),
+            vlog_gc_lock: Arc::default(),
+            data_dir,
+            versions,
+            prefetch_pool,
+            comparator,
+            snapshots: Arc::default(),
+            write_stall,
+            scheduler,
+            errors,
+        };
+        // 恢复过程中可能向 level-0 中写入了文件
+        engine.schedule_compaction()?;
+        Ok(engine)
+    }
+
+    /// 等待后台的 flush 和 compaction 全部完成
+    pub fn wait_idle(&self) {
+        self.scheduler.wait_idle();
+    }
+
+    /// 关闭后台任务并将 vLog 和 WAL fsync 到磁盘，之后的写入都会失败
+    ///
+    /// `ShutdownMode::Wait` 等待队列中的 flush 和 compaction 全部完成；
+    /// `ShutdownMode::Cancel` 丢弃尚未开始的任务，没有 flush 的内存表在下次打开时从 WAL 中恢复。
+    /// drop 时以 `ShutdownMode::Cancel` 方式关闭
+    pub fn shutdown(&mut self, mode: ShutdownMode) -> Result<()> {
+        self.scheduler.shutdown(mode);
+        self.vlog.write().unwrap().sync()?;
+        self.wal_writer.lock().unwrap().sync()
+    }
+
+    /// 存储引擎当前的状态
+    pub fn state(&self) -> EngineState {
+        if self.scheduler.is_shut_down() {
+            return EngineState::ShutDown;
+        }
+        match self.errors.error() {
+            None => EngineState::Normal,
+            Some(err) if err.severity == ErrorSeverity::Fatal => EngineState::Failed(err),
+            Some(err) => EngineState::ReadOnly(err),
+        }
+    }
+
+    /// 后台错误的原因修复之后恢复写入
+    ///
+    /// WAL 和 vLog 切换到新的文件，丢弃写入失败时残留的数据；之后同步地 flush 所有等待中的不可变内存表，
+    /// 全部成功之后清除错误并重新检查 compaction。任意一步失败时保持只读，并将失败的原因返回给调用方。
+    /// `ErrorSeverity::Fatal` 错误不能恢复
+    pub fn resume(&mut self) -> Result<()> {
+        let error = match self.state() {
+            EngineState::Normal => return Ok(()),
+            EngineState::ReadOnly(error) => error,
+            EngineState::Failed(error) => {
+                return Err(anyhow::Error::from(WiscError::EngineFailed(
+                    error.to_string(),
+                )))
+            }
+            EngineState::ShutDown => return Err(anyhow::Error::from(WiscError::ShutDown)),
+        };
+        info!("尝试从后台错误中恢复: {}", error);
+        self.wal_writer.lock().unwrap().reset()?;
+        self.vlog.write().unwrap().rotate()?;
+        flush_immutables(
+            &self.mem_tables.read().unwrap().immutables(),
+            &self.wal_dir,
+            &self.data_dir,
+            &self.versions,
+            &self.write_stall,
+        )?;
+        self.errors.clear();
+        info!("已经恢复写入");
+        self.schedule_compaction()
+    }
+
+    /// 启动时重放 WAL 丢弃的字节范围，为空表示所有的 log 都完整地恢复了
+    pub fn wal_dropped_ranges(&self) -> &[DroppedRange] {
+        &self.wal_dropped
+    }
+
+    /// 当前的文件集合
+    fn current_version(&self) -> Arc<Version> {
+        self.versions.lock().unwrap().current()
+    }
+
+    /// 在当前最新的数据上创建快照
+    ///
+    /// 通过快照读取时只能看到创建快照之前的写入，快照存活期间 compaction 会保留对它可见的版本，
+    /// vLog 也不会进行垃圾回收，因此不再使用的快照应当尽快 drop
+    pub fn snapshot(&self) -> Snapshot {
+        // 持有写锁时，已经分配出去的 sequence 都已经插入了内存表，快照不会在之后看到更小的 sequence
+        let _writer = self.wal_writer.lock().unwrap();
+        SnapshotList::acquire(&self.snapshots)
+    }
+
+    /// 通过快照读取 key
+    pub fn get_at(&self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<ByteVec>> {
+        self.get_at_sequence(key, snapshot.sequence())
+    }
+
+    /// 通过快照进行范围查询
+    pub fn scan_at(&self, snapshot: &Snapshot, range: Scans) -> Result<Vec<(ByteVec, ByteVec)>> {
+        self.scan_at_sequence(range, snapshot.sequence())
+    }
+
+    /// 存储引擎的统计信息
+    pub fn statistics(&self) -> EngineStatistics {
+        let version = self.current_version();
+        let tables = version.table_cache();
+        EngineStatistics {
+            block_cache: tables.block_cache().statistics(),
+            table_cache: tables.statistics(),
+            filter: tables.filter_statistics(),
+            write_stall: self.write_stall.statistics(),
+            background: self.scheduler.statistics(),
+            wal_syncs: self.group_commit.syncs(),
+        }
+    }
+
+    /// 查找 key 中 sequence 不大于 `sequence` 的最新版本（可能是删除标记）
+    fn get_internal(&self, key: &[u8], sequence: i64) -> Result<Option<Key>> {
+        get_internal(
+            &self.mem_tables,
+            &self.versions,
+            &self.data_dir,
+            key,
+            sequence,
+        )
+    }
+
+    /// 读取 key 在 `sequence` 时的 value，若可见的版本为删除标记则视为不存在
+    fn get_at_sequence(&self, key: &[u8], sequence: i64) -> Result<Option<ByteVec>> {
+        self.errors.check_readable()?;
+        match self.get_internal(key, sequence)? {
+            Some(internal_key) if !internal_key.is_deleted() => {
+                Ok(Some(self.resolve_value(&internal_key)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 范围查询 `sequence` 时的数据
+    ///
+    /// 将内存表和所有层级数据文件中可见的版本归并，每个 key 只保留最新版本并跳过删除标记。
+    /// 先确定范围内所有的 key 和指针，再使用线程池并行地从 vLog 中读取 value，结果依然按照 key 排序
+    fn scan_at_sequence(&self, range: Scans, sequence: i64) -> Result<Vec<(ByteVec, ByteVec)>> {
+        self.errors.check_readable()?;
+        let mut sources = self.mem_tables.read().unwrap().scan(&range, sequence);
+        sources.append(
+            &mut self
+                .current_version()
+                .scan(&self.data_dir, &range, sequence)?,
+        );
+        let live = MergingIterator::new(
+            sources.into_iter().map(Vec::into_iter).collect(),
+            self.comparator.clone(),
+        )
+        .filter(|internal_key| !internal_key.is_deleted());
+        let live: Vec<Key> = match range.limit {
+            Some(limit) => live.take(limit).collect(),
+            None => live.collect(),
+        };
+        self.prefetch_pool.install(|| {
+            live.par_iter()
+                .map(|internal_key| {
+                    Ok((
+                        internal_key.key().to_vec(),
+                        self.resolve_value(internal_key)?,
+                    ))
+                })
+                .collect()
         })
     }
+
+    /// 判断 `level` 是否是 key 所在的最深层级，即更深的层级中不存在该 key 的任何版本
+    ///
+    /// 压缩时只有在最深层级才可以真正丢弃墓碑，否则更深层级中的旧版本会重新变得可见
+    pub fn is_base_level_for_key(&self, key: &[u8], level: u8) -> Result<bool> {
+        Ok(!self
+            .current_version()
+            .key_exists_below(&self.data_dir, key, level)?)
+    }
+
+    /// 获取 `Key` 对应的用户 value，value 存放在 vLog 中时根据指针读取
+    fn resolve_value(&self, internal_key: &Key) -> Result<ByteVec> {
+        if internal_key.is_value_pointer() {
+            self.vlog
+                .read()
+                .unwrap()
+                .read(&ValuePointer::decode(internal_key.value())?)
+        } else {
+            Ok(internal_key.value().to_vec())
+        }
+    }
+
+    /// vLog 垃圾回收使用的存储引擎状态
+    fn vlog_gc(&self) -> VlogGc {
+        VlogGc {
+            wal_writer: self.wal_writer.clone(),
+            mem_tables: self.mem_tables.clone(),
+            vlog: self.vlog.clone(),
+            versions: self.versions.clone(),
+            data_dir: self.data_dir.clone(),
+            snapshots: self.snapshots.clone(),
+            errors: self.errors.clone(),
+            running: self.vlog_gc_lock.clone(),
+        }
+    }
+
+    /// vLog 切换文件之后，如果已经封存的文件总大小超过阈值，提交一个低优先级的后台任务回收最旧的文件
+    ///
+    /// 写入不等待回收完成，此时写入已经成功，检查或者提交失败只记录日志；
+    /// 回收本身失败时由调度器记录到 `ErrorHandler`
+    fn maybe_vlog_gc(&self) {
+        if let Err(err) = self.schedule_vlog_gc(
+            SERVER_CONFIG.vlog_gc_size_threshold,
+            SERVER_CONFIG.vlog_gc_ratio,
+        ) {
+            error!("提交 vLog 垃圾回收任务失败: {:?}", err);
+        }
+    }
+
+    fn schedule_vlog_gc(&self, size_threshold: u64, min_garbage_ratio: f64) -> Result<()> {
+        if self.vlog.read().unwrap().sealed_size()? < size_threshold {
+            return Ok(());
+        }
+        let gc = self.vlog_gc();
+        self.scheduler
+            .schedule(JobKind::Compaction, JobPriority::Low, move |job| {
+                if job.is_cancelled() {
+                    return Ok(());
+                }
+                gc.collect_oldest(size_threshold, min_garbage_ratio)
+            })
+    }
+
+    /// 原子地写入一批 put 和 delete，可以被多个线程同时调用
+    ///
+    /// batch 中的操作使用一段连续的 sequence，较大的 value 先写入 vLog，
+    /// 之后所有的 key 作为一条 WAL record 写入并一起插入 memtable。
+    /// group_commit 方式下释放写锁之后才等待 fsync，同时等待的写入共享同一次 fsync
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut internal_keys = Vec::with_capacity(batch.len());
+        let mut separated = false;
+        for op in batch.ops() {
+            // sequence 在持有写锁时分配
+            let internal_key = match op {
+                BatchOp::Put(key, value) if ValueLog::should_separate(value) => {
+                    separated = true;
+                    let pointer = self.append_value(key, value)?;
+                    Key::with_sequence(key.clone(), pointer.encode(), DataType::ValuePointer, 0)
+                }
+                BatchOp::Put(key, value) => {
+                    Key::with_sequence(key.clone(), value.clone(), DataType::Set, 0)
+                }
+                BatchOp::Delete(key) => {
+                    Key::with_sequence(key.clone(), ByteVec::new(), DataType::Delete, 0)
+                }
+            };
+            internal_keys.push(internal_key);
+        }
+        if separated {
+            self.sync_vlog_before_wal()?;
+        }
+        let ticket = {
+            let mut wal_writer = self.wal_writer.lock().unwrap();
+            self.write_locked(&mut wal_writer, &mut internal_keys)?
+        };
+        if let Some(ticket) = ticket {
+            if let Err(err) = self.group_commit.sync(ticket, &self.wal_sync_file) {
+                self.errors.record(ErrorSource::Write, &err);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// 持有写锁时的写入流程：分配 sequence，先写 WAL，再写 memtable
+    ///
+    /// 所有的 key 作为一条 WAL record 写入，group_commit 方式下返回等待 fsync 的 ticket
+    fn write_locked(
+        &self,
+        wal_writer: &mut LogRecordWrite,
+        internal_keys: &mut [Key],
+    ) -> Result<Option<u64>> {
+        self.make_room_for_write(wal_writer)?;
+        assign_sequences(internal_keys);
+        append_locked(wal_writer, &self.mem_tables, &self.errors, internal_keys)
+    }
+
+    /// 根据 level-0 的文件数限制写入：超过 slowdown 阈值时延迟本次写入，
+    /// 达到 stop 阈值时阻塞，直到 compaction 减少了 level-0 的文件。
+    /// 之后如果 mut_table 已经写满，切换内存表
+    fn make_room_for_write(&self, wal_writer: &mut LogRecordWrite) -> Result<()> {
+        self.check_writable()?;
+        let level_0_files = || self.current_version().files(0).len();
+        if level_0_files() >= LEVEL_0_SLOWDOWN_TRIGGER {
+            self.write_stall.delay();
+        }
+        self.write_stall.wait_while(StallCause::Level0Stop, || {
+            level_0_files() >= LEVEL_0_STOP_TRIGGER
+        });
+        if self.mem_tables.read().unwrap().should_switch() {
+            self.switch_memtable(wal_writer)?;
+        }
+        // 阻塞期间后台任务可能失败了
+        self.check_writable()
+    }
+
+    /// 存在后台错误或者存储引擎关闭之后拒绝写入
+    fn check_writable(&self) -> Result<()> {
+        if self.scheduler.is_shut_down() {
+            return Err(anyhow::Error::from(WiscError::ShutDown));
+        }
+        self.errors.check_writable()
+    }
+
+    /// WAL 和内存表同时切换：写满的 mut_table 连同它的 log 文件一起交给 flush 线程，
+    /// 之后的写入进入新的 log 文件和新的 mut_table
+    fn switch_memtable(&self, wal_writer: &mut LogRecordWrite) -> Result<()> {
+        let full_log = match wal_writer.rotate() {
+            Ok(full_log) => full_log,
+            Err(err) => {
+                self.errors.record(ErrorSource::Write, &err);
+                return Err(err);
+            }
+        };
+        info!("切换内存表，写满的 log 文件: {:?}", full_log);
+        // 只持有读锁等待不可变队列空出位置，等待期间不影响读取
+        self.mem_tables
+            .read()
+            .unwrap()
+            .wait_for_room(&self.write_stall);
+        self.mem_tables
+            .write()
+            .unwrap()
+            .switch(log_number(&full_log).unwrap_or(0), &self.write_stall);
+        self.schedule_flush()
+    }
+
+    /// 提交 flush 任务，flush 完成之后接着提交 compaction 任务
+    fn schedule_flush(&self) -> Result<()> {
+        let immutables = self.mem_tables.read().unwrap().immutables();
+        let wal_dir = self.wal_dir.clone();
+        let data_dir = self.data_dir.clone();
+        let versions = self.versions.clone();
+        let snapshots = self.snapshots.clone();
+        let write_stall = self.write_stall.clone();
+        self.scheduler
+            .schedule(JobKind::Flush, JobPriority::High, move |job| {
+                flush_immutables(&immutables, &wal_dir, &data_dir, &versions, &write_stall)?;
+                // level-0 中新增了文件，检查是否需要 major compaction
+                job.schedule(
+                    JobKind::Compaction,
+                    compaction_priority(&versions),
+                    compaction_job(versions, data_dir, snapshots, write_stall),
+                );
+                Ok(())
+            })
+    }
+
+    fn schedule_compaction(&self) -> Result<()> {
+        self.scheduler.schedule(
+            JobKind::Compaction,
+            compaction_priority(&self.versions),
+            compaction_job(
+                self.versions.clone(),
+                self.data_dir.clone(),
+                self.snapshots.clone(),
+                self.write_stall.clone(),
+            ),
+        )
+    }
+
+    /// 将 value 追加到 vLog，失败时切换为只读模式
+    ///
+    /// 写入失败之后 vLog 文件中可能残留了不完整的 entry，resume 时切换到新的文件。
+    /// 追加时切换了文件则检查是否需要垃圾回收
+    fn append_value(&self, key: &[u8], value: &[u8]) -> Result<ValuePointer> {
+        self.check_writable()?;
+        let (pointer, rotated) = {
+            let mut vlog = self.vlog.write().unwrap();
+            let pointer = vlog.append(key, value).inspect_err(|err| {
+                self.errors.record(ErrorSource::Write, err);
+            })?;
+            (pointer, vlog.take_rotated())
+        };
+        if rotated {
+            self.maybe_vlog_gc();
+        }
+        Ok(pointer)
+    }
+
+    /// WAL 中的指针持久化之前，它指向的 value 必须已经持久化
+    fn sync_vlog_before_wal(&self) -> Result<()> {
+        if matches!(
+            SERVER_CONFIG.wal_sync_mode,
+            WalSyncMode::EveryWrite | WalSyncMode::GroupCommit
+        ) {
+            if let Err(err) = self.vlog.write().unwrap().sync() {
+                self.errors.record(ErrorSource::Write, &err);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
 }
 impl KvsEngine for LsmLogEngine {
     /// 用户的set操作
-    fn set(&mut self, key: &str, value: &str) -> Result<()> {
-        let internal_key = Key::new(key.to_string(), value.to_string(), DataType::Set);
+    ///
+    /// value 达到阈值时先追加到 vLog，WAL 和 memtable 中只保存指针
+    fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
+    /// 用户的get操作
+    ///
+    /// 若最新版本为删除标记则视为不存在
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<ByteVec>> {
+        self.get_at_sequence(key, MAX_SEQUENCE)
+    }
+
+    /// 事务使用的读取，compaction 丢弃删除标记会改变最新版本的 sequence，
+    /// 此时事务提交会失败，但不会错过真正的修改；vLog 垃圾回收搬迁 value 时保留原来的 sequence
+    fn get_with_sequence(&self, key: &[u8]) -> Result<(Option<ByteVec>, i64)> {
+        self.errors.check_readable()?;
+        match self.get_internal(key, MAX_SEQUENCE)? {
+            Some(internal_key) if !internal_key.is_deleted() => Ok((
+                Some(self.resolve_value(&internal_key)?),
+                internal_key.sequence(),
+            )),
+            Some(internal_key) => Ok((None, internal_key.sequence())),
+            None => Ok((None, 0)),
+        }
+    }
+
+    /// 用户的scan操作
+    ///
+    /// 每个 key 只返回最新版本并跳过删除标记，结果按照 key 排序
+    fn scan_bytes(&self, range: Scans) -> Result<Vec<(ByteVec, ByteVec)>> {
+        self.scan_at_sequence(range, MAX_SEQUENCE)
+    }
 
-        // 写 WAL 的逻辑先于其他逻辑，这里失败就会返回用户此次操作失败
-        // is_new_log: 是否开启了新的日志文件
-        if let Some(new_log_path) = self.wal_writer.add_records(&internal_key)? {
-            info!("开启了新的日志文件");
-            // 如果开启了新的日志文件，
-            // 1 表示当前的key已经被添加到 新的log文件中了，需要调换table,
-            // 调换 两个table的状态（只是修改状态不涉及其它修改）
-            self.mem_tables.exchange();
-            // 2 同时当前的 memtable 就需要 flush
-            minor_compact(
-                self.mem_tables.imu_table().unwrap().table.clone(),
-                Arc::new(Mutex::new(new_log_path)))?;
+    /// 用户的remove操作
+    ///
+    /// 删除并不会立即移除数据，而是写入一个 `DataType::Delete` 的墓碑，
+    /// 读取时遇到墓碑视为不存在，直到压缩时确认更深的层级已经没有该 key 的旧版本才真正丢弃
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(anyhow::Error::from(WiscError::KeyNotExist(
+                String::from_utf8_lossy(key).to_string(),
+            )));
         }
-        // 将数据写入内存表
-        self.mem_tables.add_record(&internal_key);
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    /// 手动触发 vLog 垃圾回收
+    ///
+    /// 依次回收所有已经封存且存在垃圾的 vLog 文件，返回回收的总字节数
+    fn gc(&mut self) -> Result<u64> {
+        self.vlog_gc().collect_all()
+    }
+
+    fn stats(&self) -> Result<String> {
+        Ok(format!("{:#?}", self.statistics()))
+    }
+
+    fn state(&self) -> String {
+        LsmLogEngine::state(self).to_string()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        LsmLogEngine::resume(self)
+    }
+
+    /// block 缓存由所有 version 共享，调整之后对所有的读取立即生效
+    fn set_block_cache_capacity(&self, capacity: usize) -> Result<()> {
+        self.current_version()
+            .table_cache()
+            .block_cache()
+            .set_capacity(capacity);
         Ok(())
     }
 
-    #[warn(unused_variables)]
-    fn get(&self, _key: &str) -> Result<Option<String>> {
-        todo!()
+    /// 用户的 batch 操作，见 `LsmLogEngine::write`
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.write(batch)
     }
+}
+
+impl Drop for LsmLogEngine {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown(ShutdownMode::Cancel) {
+            error!("关闭存储引擎失败: {:?}", err);
+        }
+    }
+}
+
+/// 将不可变内存表按照从旧到新的顺序 flush 到 level-0
+///
+/// 每个内存表都会在 level-0 中生成一个新的 SSTable，
+/// 数据文件写完并 fsync、并且提交到 MANIFEST 之后才会将内存表移出队列并删除已经持久化的 log 文件。
+/// 同一时刻只有一个线程 flush，其他线程等待之后发现队列已空即返回
+fn flush_immutables(
+    immutables: &ImmutableQueue,
+    wal_dir: &Path,
+    data_dir: &Path,
+    versions: &Mutex<VersionSet>,
+    write_stall: &WriteStall,
+) -> Result<()> {
+    let _flushing = immutables.lock_flush();
+    while let Some(immutable) = immutables.oldest() {
+        info!("当前imu_table len{}", immutable.table.len());
+        // 该内存表的 log 文件以及之前的所有 log 都已经持久化
+        let persisted_log_number = immutable.log_number + 1;
+        flush_memtable(
+            &immutable.table,
+            versions,
+            data_dir,
+            Some(persisted_log_number),
+        )?;
+        immutables.pop_oldest();
+        // 不可变队列中空出了位置，唤醒等待切换内存表的写入
+        write_stall.notify();
+        // 之后删除该内存表对应的log 文件，以及恢复时遗留的更早的 log 文件
+        remove_persisted_logs(wal_dir, persisted_log_number)?;
+    }
+    Ok(())
+}
+
+/// level-0 的文件数已经导致写入被限流时，compaction 优先执行
+fn compaction_priority(versions: &Mutex<VersionSet>) -> JobPriority {
+    if versions.lock().unwrap().current().files(0).len() >= LEVEL_0_SLOWDOWN_TRIGGER {
+        JobPriority::High
+    } else {
+        JobPriority::Low
+    }
+}
+
+/// compaction 任务：持续压缩直到所有层级的得分都小于 1，调度器取消之后在两次压缩之间退出
+fn compaction_job(
+    versions: Arc<Mutex<VersionSet>>,
+    data_dir: PathBuf,
+    snapshots: Arc<SnapshotList>,
+    write_stall: Arc<WriteStall>,
+) -> impl FnOnce(&JobContext) -> Result<()> + Send + 'static {
+    move |job| {
+        while !job.is_cancelled() && compact_once(&versions, &data_dir, &snapshots, &write_stall)? {
+        }
+        Ok(())
+    }
+}
+
+/// 查找 key 中 sequence 不大于 `sequence` 的最新版本（可能是删除标记）
+///
+/// 查找顺序：mut_table -> 不可变内存表（从新到旧） -> level-0（从新到旧） -> level-1..6，
+/// 先找到的版本即最新版本
+fn get_internal(
+    mem_tables: &RwLock<MemTables>,
+    versions: &Mutex<VersionSet>,
+    data_dir: &Path,
+    key: &[u8],
+    sequence: i64,
+) -> Result<Option<Key>> {
+    if let Some(internal_key) = mem_tables.read().unwrap().get(key, sequence) {
+        return Ok(Some(internal_key));
+    }
+    let version = versions.lock().unwrap().current();
+    version.get(data_dir, key, sequence)
+}
+
+/// 为一批 key 分配一段连续的 sequence，需要持有写锁
+///
+/// sequence 的分配和插入内存表都在写锁中完成，快照不会看到之后才插入的更小的 sequence
+fn assign_sequences(internal_keys: &mut [Key]) {
+    let first_sequence = gen_sequence_range(internal_keys.len());
+    for (i, internal_key) in internal_keys.iter_mut().enumerate() {
+        internal_key.set_sequence(first_sequence + i as i64);
+    }
+}
+
+/// 持有写锁时先写 WAL，再写 memtable
+///
+/// 所有的 key 作为一条 WAL record 写入，group_commit 方式下返回等待 fsync 的 ticket
+fn append_locked(
+    wal_writer: &mut LogRecordWrite,
+    mem_tables: &RwLock<MemTables>,
+    errors: &ErrorHandler,
+    internal_keys: &[Key],
+) -> Result<Option<u64>> {
+    // 写 WAL 的逻辑先于其他逻辑，这里失败就会返回用户此次操作失败，
+    // 之后切换为只读模式，log 文件中可能残留了不完整的 record
+    let ticket = match wal_writer.append_batch(internal_keys) {
+        Ok(ticket) => ticket,
+        Err(err) => {
+            errors.record(ErrorSource::Write, &err);
+            return Err(err);
+        }
+    };
+    // 将数据写入内存表
+    let mem_tables = mem_tables.read().unwrap();
+    for internal_key in internal_keys {
+        mem_tables.add_record(internal_key);
+    }
+    Ok(ticket)
+}
+
+/// vLog 垃圾回收使用的存储引擎状态，后台任务和手动触发的 `gc` 共用
+///
+/// 扫描文件、查找每条 entry 的最新版本以及追加存活的 entry 都不持有写锁，
+/// 只有重新确认指针并写入新的指针时才持有，回收期间写入只会被短暂阻塞
+#[derive(Debug, Clone)]
+struct VlogGc {
+    wal_writer: Arc<Mutex<LogRecordWrite>>,
+    mem_tables: Arc<RwLock<MemTables>>,
+    vlog: Arc<RwLock<ValueLog>>,
+    versions: Arc<Mutex<VersionSet>>,
+    data_dir: PathBuf,
+    snapshots: Arc<SnapshotList>,
+    errors: Arc<ErrorHandler>,
+    /// 回收期间持有，同一时刻只回收一个文件
+    running: Arc<Mutex<()>>,
+}
+impl VlogGc {
+    /// 后台任务：已经封存的文件总大小依然超过阈值时回收最旧的文件
+    ///
+    /// 任务在队列中等待期间可能已经有其他回收完成，因此执行时重新检查
+    fn collect_oldest(&self, size_threshold: u64, min_garbage_ratio: f64) -> Result<()> {
+        let _running = self.running.lock().unwrap();
+        let oldest = {
+            let vlog = self.vlog.read().unwrap();
+            if vlog.sealed_size()? < size_threshold {
+                return Ok(());
+            }
+            vlog.sealed_file_ids()?.first().copied()
+        };
+        if let Some(file_id) = oldest {
+            if let Some(report) = self.collect(file_id, min_garbage_ratio)? {
+                info!("vLog 垃圾回收: {:?}", report);
+            }
+        }
+        Ok(())
+    }
+
+    /// 依次回收所有已经封存且存在垃圾的 vLog 文件，返回回收的总字节数
+    fn collect_all(&self) -> Result<u64> {
+        let _running = self.running.lock().unwrap();
+        let mut reclaimed_bytes = 0;
+        let sealed = self.vlog.read().unwrap().sealed_file_ids()?;
+        for file_id in sealed {
+            if let Some(report) = self.collect(file_id, 0.0)? {
+                info!("vLog 垃圾回收: {:?}", report);
+                reclaimed_bytes += report.reclaimed_bytes;
+            }
+        }
+        Ok(reclaimed_bytes)
+    }
+
+    /// 回收单个 vLog 文件，调用方需要持有 `running`
+    ///
+    /// 逐条检查 LSM 中该 key 的最新版本是否仍然指向这条 entry，垃圾比例低于 `min_garbage_ratio`
+    /// 或者没有任何垃圾时不回收，返回 None。仍然存活的 entry 重新追加到 vLog 头部，
+    /// 之后持有写锁再确认一次，扫描之后被覆盖或者删除的 key 不再写入新的指针。
+    /// 存在快照时不回收，快照可能仍然需要读取旧版本的 value。
+    /// 新的 entry 和指向它们的 WAL 都持久化之后才删除整个旧文件
+    fn collect(&self, file_id: u64, min_garbage_ratio: f64) -> Result<Option<VlogGcReport>> {
+        if !self.snapshots.is_empty() {
+            return Ok(None);
+        }
+        let (entries, total_bytes) = {
+            let vlog = self.vlog.read().unwrap();
+            (vlog.read_file_entries(file_id)?, vlog.file_size(file_id)?)
+        };
+        let mut live = Vec::new();
+        for (pointer, entry) in entries {
+            if self.current_sequence(&entry.key, &pointer)?.is_some() {
+                live.push((pointer, entry));
+            }
+        }
+        let live_bytes: u64 = live.iter().map(|(pointer, _)| pointer.len).sum();
+        let reclaimed_bytes = total_bytes - live_bytes;
+        if reclaimed_bytes == 0
+            || (reclaimed_bytes as f64) < (total_bytes as f64) * min_garbage_ratio
+        {
+            return Ok(None);
+        }
+
+        // 没有被写入新指针的 entry 成为新文件中的垃圾，留给之后的回收
+        let mut relocated = Vec::with_capacity(live.len());
+        {
+            let mut vlog = self.vlog.write().unwrap();
+            for (pointer, entry) in live {
+                let new_pointer = vlog.append(&entry.key, &entry.value)?;
+                relocated.push((pointer, entry.key, new_pointer));
+            }
+            vlog.sync()?;
+        }
 
-    #[warn(unused_variables)]
-    fn scan(&self, _range: Scans) -> Result<Option<Vec<String>>> {
-        todo!()
+        {
+            let mut wal_writer = self.wal_writer.lock().unwrap();
+            self.errors.check_writable()?;
+            // 快照在持有写锁时创建，确认之后直到释放写锁都不会出现新的快照
+            if !self.snapshots.is_empty() {
+                return Ok(None);
+            }
+            // 新的指针保留原来的 sequence，搬迁不是新的写入，读取过该 key 的事务不会因此冲突。
+            // 新的指针写入更新的内存表，读取和 compaction 都会优先选择它
+            let mut internal_keys = Vec::with_capacity(relocated.len());
+            for (pointer, key, new_pointer) in relocated {
+                if let Some(sequence) = self.current_sequence(&key, &pointer)? {
+                    internal_keys.push(Key::with_sequence(
+                        key,
+                        new_pointer.encode(),
+                        DataType::ValuePointer,
+                        sequence,
+                    ));
+                }
+            }
+            // 只追加少量指针，不检查内存表是否写满，由之后的写入切换
+            if !internal_keys.is_empty() {
+                append_locked(
+                    &mut wal_writer,
+                    &self.mem_tables,
+                    &self.errors,
+                    &internal_keys,
+                )?;
+                wal_writer.sync()?;
+            }
+        }
+        // 之后的读取只会读到新的指针
+        self.vlog.read().unwrap().remove_file(file_id)?;
+        Ok(Some(VlogGcReport {
+            file_id,
+            total_bytes,
+            live_bytes,
+            reclaimed_bytes,
+        }))
     }
 
-    #[warn(unused_variables)]
-    fn remove(&mut self, _key: &str) -> Result<()> {
-        todo!()
+    /// LSM 中 key 的最新版本仍然指向 `pointer` 时返回该版本的 sequence
+    fn current_sequence(&self, key: &[u8], pointer: &ValuePointer) -> Result<Option<i64>> {
+        match get_internal(
+            &self.mem_tables,
+            &self.versions,
+            &self.data_dir,
+            key,
+            MAX_SEQUENCE,
+        )? {
+            Some(internal_key)
+                if internal_key.is_value_pointer()
+                    && ValuePointer::decode(internal_key.value())? == *pointer =>
+            {
+                Ok(Some(internal_key.sequence()))
+            }
+            _ => Ok(None),
+        }
     }
 }
 
-/// 将当前的 imu_table flush到 level-0
-fn minor_compact(
-    imu_table: Arc<SkipMap<String, Key>>,
-    write_log_path: Arc<Mutex<PathBuf>>
+/// interval 持久化方式下的后台 fsync 线程，存储引擎关闭之后退出
+///
+/// 先 fsync vLog 再 fsync WAL，保证 WAL 中持久化的指针指向的 value 同样已经持久化
+fn interval_sync(
+    wal: Weak<Mutex<File>>,
+    vlog: Weak<Mutex<File>>,
+    errors: Arc<ErrorHandler>,
+    interval: Duration,
 ) -> Result<()> {
     thread::Builder::new()
-        .name(MINOR_THREAD.to_string())
-        .spawn(move || -> Result<()> {
-            info!("当前imu_table len{}", &imu_table.len());
-            // TODO  测试代码
-            let mut file = OpenOptions::new()
-                .write(true)
-                .append(true)
-                .open("b.txt")?;
-            // 每次一次切换写入一个 |#| 块
-            file.write_all(b"|#|")?;
-            file.flush()?;
-
-            imu_table.clear();
-            // 之后删除该imu_table 对应的log 文件
-            remove_file(write_log_path.lock().unwrap().as_path())?;
-            Ok(())
+        .name(WAL_SYNC_THREAD.to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let (wal, vlog) = match (wal.upgrade(), vlog.upgrade()) {
+                (Some(wal), Some(vlog)) => (wal, vlog),
+                _ => return,
+            };
+            let result = vlog.lock().unwrap().sync_data();
+            if let Err(err) = result {
+                error!("vLog fsync 失败: {:?}", err);
+                errors.record(ErrorSource::WalSync, &anyhow::Error::from(err));
+                continue;
+            }
+            let result = wal.lock().unwrap().sync_data();
+            if let Err(err) = result {
+                error!("WAL fsync 失败: {:?}", err);
+                errors.record(ErrorSource::WalSync, &anyhow::Error::from(err));
+            }
         })?;
+    Ok(())
+}
+
+/// 按照编号顺序重放所有尚未持久化的 log 文件到可变内存表中，返回被丢弃的字节范围
+///
+/// 重放的数据达到 `write_buffer_size` 时直接 flush 到 level-0，
+/// 此时不推进 MANIFEST 中的 log_number，重放中断之后再次重放只会产生重复的相同版本。
+/// 如果有数据被丢弃，恢复的数据会全部 flush 并推进 log_number，
+/// 损坏的 log 文件随之删除，避免之后的写入排在损坏的数据之后
+fn recover(
+    mem_tables: &mut MemTables,
+    versions: &Mutex<VersionSet>,
+    wal_dir: &Path,
+    data_dir: &Path,
+) -> Result<Vec<DroppedRange>> {
+    let persisted_log_number = versions.lock().unwrap().log_number();
+    remove_persisted_logs(wal_dir, persisted_log_number)?;
+    // 恢复过程中 flush 的文件以及之后新的 log 文件，编号都要大于已经存在的 log 文件
+    if let Some((number, _)) = log_files(wal_dir)?.last() {
+        restore_file_number(number + 1);
+    }
+    let mode = SERVER_CONFIG.wal_recovery_mode;
+    let table = mem_tables.mut_table();
+    let mut dropped: Vec<DroppedRange> = Vec::new();
+    let mut last_log_number = None;
+    for (number, path) in log_files(wal_dir)? {
+        last_log_number = Some(number);
+        // point-in-time 模式下，损坏位置之后的 log 文件全部丢弃
+        if mode == WalRecoveryMode::PointInTimeRecovery && !dropped.is_empty() {
+            let range = DroppedRange {
+                log_number: number,
+                start: 0,
+                end: path.metadata()?.len(),
+                reason: "之前的 log 文件中存在损坏".to_string(),
+            };
+            warn!("丢弃 log 文件 {:?} 中的数据: {:?}", path, range);
+            dropped.push(range);
+            continue;
+        }
+        let mut reader = LogRecordRead::new(&path)?;
+        reader.read_log(mode)?;
+        dropped.extend_from_slice(reader.dropped());
+        let keys = reader.into_keys();
+        info!("从 log 文件 {} 恢复 {} 条记录", number, keys.len());
+        if let Some(last_sequence) = keys.iter().map(Key::sequence).max() {
+            restore_sequence(last_sequence);
+        }
+        for key in keys {
+            table.insert(key);
+            if table.memory_usage() >= SERVER_CONFIG.write_buffer_size {
+                flush_memtable(table, versions, data_dir, None)?;
+                table.clear();
+            }
+        }
+    }
+    if let (Some(number), false) = (last_log_number, dropped.is_empty()) {
+        flush_memtable(table, versions, data_dir, Some(number + 1))?;
+        table.clear();
+        remove_persisted_logs(wal_dir, number + 1)?;
+    }
+    Ok(dropped)
+}
 
+/// 将内存表 flush 到 level-0，并与 `log_number` 一起提交到 MANIFEST
+///
+/// 内存表为空时只推进 log_number
+fn flush_memtable(
+    table: &MemTable,
+    versions: &Mutex<VersionSet>,
+    data_dir: &Path,
+    log_number: Option<u64>,
+) -> Result<()> {
+    let mut edit = VersionEdit {
+        log_number,
+        ..VersionEdit::default()
+    };
+    if !table.is_empty() {
+        let file = write_level_0_table(table, data_dir)?;
+        edit.last_sequence = Some(file.largest_seq);
+        edit.added.push(file);
+    }
+    versions.lock().unwrap().log_and_apply(edit)
+}
+
+/// 删除编号小于 log_number 的 log 文件，它们的数据都已经持久化到 SSTable 中
+fn remove_persisted_logs(wal_dir: &Path, log_number: u64) -> Result<()> {
+    for (number, path) in log_files(wal_dir)? {
+        if number < log_number {
+            remove_file(path)?;
+        }
+    }
     Ok(())
 }
 
+/// 将内存表中的所有数据按照（用户 key 升序，sequence 降序）写入一个新的 level-0 SSTable
+///
+/// 内存表本身即按照 internal key 排列，顺序写入即可
+fn write_level_0_table(table: &MemTable, data_dir: &Path) -> Result<FileMetaData> {
+    let number = gen_file_number();
+    let mut builder = TableBuilder::new(
+        LevelDir::new(data_dir, 0).file_path(number)?,
+        CompressionType::for_level(0),
+    )?;
+    for entry in table.iter() {
+        builder.add(entry.value())?;
+    }
+    let file = FileMetaData::new(0, number, builder.finish()?);
+    info!("level-0 SSTable 写入完毕: {:?}", file);
+    Ok(file)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::common::fn_util::log_init;
+    use std::fs::{self, remove_dir_all, OpenOptions};
+    use std::io;
+    use std::ops::Bound;
+
+    /// 在临时目录中打开一个干净的存储引擎，避免并行执行的测试之间相互影响
+    fn open_temp(name: &str) -> Result<LsmLogEngine> {
+        let root = env::temp_dir().join("r_wisckey").join(name);
+        let _ = remove_dir_all(&root);
+        LsmLogEngine::open_at(&root)
+    }
+
+    #[test]
+    fn get_test() -> Result<()> {
+        let mut engine = open_temp("get_test")?;
+        engine.set("get_test_key", "v1")?;
+        engine.set("get_test_key", "v2")?;
+        assert_eq!(engine.get("get_test_key")?, Some("v2".to_string()));
+        assert_eq!(engine.get("get_test_key_none")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn value_separation_test() -> Result<()> {
+        let mut engine = open_temp("value_separation_test")?;
+        let large = "v".repeat(SERVER_CONFIG.value_threshold);
+        engine.set("value_separation_test_key", &large)?;
+        assert!(engine
+            .mem_tables
+            .read()
+            .unwrap()
+            .get(b"value_separation_test_key", MAX_SEQUENCE)
+            .unwrap()
+            .is_value_pointer());
+        assert_eq!(
+            engine.get("value_separation_test_key")?,
+            Some(large.clone())
+        );
+
+        let range = Scans::new(
+            Bound::Included(b"value_separation_test_key".to_vec()),
+            Bound::Included(b"value_separation_test_key".to_vec()),
+            None,
+        );
+        assert_eq!(
+            engine.scan(range)?,
+            vec![("value_separation_test_key".to_string(), large)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_scan_test() -> Result<()> {
+        let mut engine = open_temp("parallel_scan_test")?;
+        let mut expected = Vec::new();
+        for i in 0..64 {
+            let key = format!("key_{:02}", i);
+            // 一半的 value 分离存储，一半内联存储
+            let value = if i % 2 == 0 {
+                format!("{:0>width$}", i, width = SERVER_CONFIG.value_threshold)
+            } else {
+                i.to_string()
+            };
+            engine.set(&key, &value)?;
+            expected.push((key, value));
+        }
+        let range = Scans::new(Bound::Unbounded, Bound::Unbounded, None);
+        assert_eq!(engine.scan(range)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn vlog_gc_test() -> Result<()> {
+        let mut engine = open_temp("vlog_gc_test")?;
+        let large = |tag: &str| tag.repeat(SERVER_CONFIG.value_threshold);
+        engine.set("k_over", &large("1"))?;
+        engine.set("k_over", &large("2"))?;
+        engine.set("k_remv", &large("3"))?;
+        engine.remove("k_remv")?;
+        engine.set("k_live", &large("4"))?;
+        engine.vlog.write().unwrap().rotate()?;
+        let sealed = engine.vlog.write().unwrap().sealed_file_ids()?;
+        assert_eq!(sealed.len(), 1);
+
+        // 4 条 entry 中只有 2 条存活
+        let entry_len = engine.vlog.write().unwrap().file_size(sealed[0])? / 4;
+        assert_eq!(engine.gc()?, entry_len * 2);
+        assert!(engine.vlog.write().unwrap().sealed_file_ids()?.is_empty());
+        assert_eq!(engine.get("k_over")?, Some(large("2")));
+        assert_eq!(engine.get("k_live")?, Some(large("4")));
+        assert_eq!(engine.get("k_remv")?, None);
+
+        // 没有垃圾时不会回收
+        engine.vlog.write().unwrap().rotate()?;
+        assert_eq!(engine.gc()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn vlog_gc_transaction_test() -> Result<()> {
+        let root = env::temp_dir()
+            .join("r_wisckey")
+            .join("vlog_gc_transaction_test");
+        let _ = remove_dir_all(&root);
+        let large = |tag: &str| tag.repeat(SERVER_CONFIG.value_threshold);
+        let sequence = {
+            let mut engine = LsmLogEngine::open_at(&root)?;
+            engine.set("gc_txn_key", &large("1"))?;
+            engine.set("gc_txn_garbage", &large("2"))?;
+            engine.set("gc_txn_garbage", &large("3"))?;
+            let sequence = engine.get_with_sequence(b"gc_txn_key")?.1;
+
+            let mut txn = engine.begin();
+            assert_eq!(
+                txn.get(&engine, b"gc_txn_key")?,
+                Some(large("1").into_bytes())
+            );
+            txn.put(b"gc_txn_out", b"v1");
+            engine.vlog.write().unwrap().rotate()?;
+            assert!(engine.gc()? > 0);
+            assert!(engine.vlog.read().unwrap().sealed_file_ids()?.is_empty());
+
+            // 搬迁之后指针改变而 sequence 不变，事务依然可以提交
+            assert_eq!(
+                engine.get_with_sequence(b"gc_txn_key")?,
+                (Some(large("1").into_bytes()), sequence)
+            );
+            engine.commit(txn)?;
+            assert_eq!(engine.get("gc_txn_out")?, Some("v1".to_string()));
+            sequence
+        };
+        // 重放 WAL 之后新的指针依然覆盖原来的指针
+        let engine = LsmLogEngine::open_at(&root)?;
+        assert_eq!(
+            engine.get_with_sequence(b"gc_txn_key")?,
+            (Some(large("1").into_bytes()), sequence)
+        );
+        assert_eq!(engine.get("gc_txn_garbage")?, Some(large("3")));
+        Ok(())
+    }
+
+    #[test]
+    fn background_vlog_gc_test() -> Result<()> {
+        let engine = open_temp("background_vlog_gc_test")?;
+        let large = |tag: &str| tag.repeat(SERVER_CONFIG.value_threshold);
+        let mut batch = WriteBatch::new();
+        batch.put("bg_over", large("1"));
+        engine.write(batch)?;
+        let mut batch = WriteBatch::new();
+        batch.put("bg_over", large("2"));
+        engine.write(batch)?;
+        engine.vlog.write().unwrap().rotate()?;
+        let sealed = engine.vlog.read().unwrap().sealed_file_ids()?;
+
+        // 垃圾比例不足时不回收
+        engine.schedule_vlog_gc(0, 0.9)?;
+        engine.wait_idle();
+        assert_eq!(engine.vlog.read().unwrap().sealed_file_ids()?, sealed);
+
+        engine.schedule_vlog_gc(0, 0.5)?;
+        engine.wait_idle();
+        assert!(engine.vlog.read().unwrap().sealed_file_ids()?.is_empty());
+        assert_eq!(engine.state(), EngineState::Normal);
+        assert_eq!(engine.get_bytes(b"bg_over")?, Some(large("2").into_bytes()));
+        Ok(())
+    }
+
+    #[test]
+    fn remove_test() -> Result<()> {
+        let mut engine = open_temp("remove_test")?;
+        engine.set("remove_test_key", "v1")?;
+        engine.remove("remove_test_key")?;
+        assert_eq!(engine.get("remove_test_key")?, None);
+
+        let err = engine.remove("remove_test_key").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WiscError>(),
+            Some(WiscError::KeyNotExist(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn flush_and_reopen_test() -> Result<()> {
+        let root = env::temp_dir()
+            .join("r_wisckey")
+            .join("flush_and_reopen_test");
+        let _ = remove_dir_all(&root);
+        let value = "v".repeat(512);
+        {
+            let mut engine = LsmLogEngine::open_at(&root)?;
+            // 写满一个内存表，触发 minor compaction
+            for i in 0..10000 {
+                engine.set(&format!("flush_key_{:05}", i), &value)?;
+            }
+            engine.wait_idle();
+            assert_eq!(engine.current_version().files(0).len(), 1);
+        }
+        // 重新打开之后，从 MANIFEST 中恢复 level-0 中的文件，从 log 中恢复内存表
+        let engine = LsmLogEngine::open_at(&root)?;
+        assert_eq!(engine.current_version().files(0).len(), 1);
+        assert_eq!(engine.get("flush_key_00000")?, Some(value.clone()));
+        assert_eq!(engine.get("flush_key_09999")?, Some(value));
+        // 位于文件 key 范围内但不存在的 key 由布隆过滤器排除，不需要读取文件
+        let before = engine.statistics().filter;
+        assert_eq!(engine.get("flush_key_05000_missing")?, None);
+        assert_eq!(engine.statistics().filter.hits, before.hits + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_test() -> Result<()> {
+        let root = env::temp_dir().join("r_wisckey").join("shutdown_test");
+        let _ = remove_dir_all(&root);
+        {
+            let mut engine = LsmLogEngine::open_at(&root)?;
+            engine.set("shutdown_a", "v1")?;
+            engine.shutdown(ShutdownMode::Wait)?;
+            // 关闭之后拒绝写入，依然可以读取
+            let err = engine.set("shutdown_b", "v1").unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<WiscError>(),
+                Some(WiscError::ShutDown)
+            ));
+            assert_eq!(engine.get("shutdown_a")?, Some("v1".to_string()));
+        }
+        let engine = LsmLogEngine::open_at(&root)?;
+        assert_eq!(engine.get("shutdown_a")?, Some("v1".to_string()));
+        assert_eq!(engine.get("shutdown_b")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_test() -> Result<()> {
+        let root = env::temp_dir().join("r_wisckey").join("resume_test");
+        let _ = remove_dir_all(&root);
+        let mut engine = LsmLogEngine::open_at(&root)?;
+        engine.set("resume_a", "v1")?;
+        assert_eq!(engine.state(), EngineState::Normal);
+
+        // 磁盘空间不足：只读，修复之后可以 resume
+        let no_space = anyhow::Error::from(io::Error::from(io::ErrorKind::StorageFull));
+        engine.errors.record(ErrorSource::Flush, &no_space);
+        assert!(matches!(engine.state(), EngineState::ReadOnly(ref err) if err.no_space));
+        let err = engine.set("resume_b", "v1").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WiscError>(),
+            Some(WiscError::ReadOnly(_))
+        ));
+        assert_eq!(engine.get("resume_a")?, Some("v1".to_string()));
+        engine.resume()?;
+        assert_eq!(engine.state(), EngineState::Normal);
+        engine.set("resume_b", "v1")?;
+        assert_eq!(engine.get("resume_b")?, Some("v1".to_string()));
+
+        // 数据损坏：读写都会失败，不能 resume
+        let corrupted = anyhow::Error::from(WiscError::WalCorrupted("1.xlog".to_string()));
+        engine.errors.record(ErrorSource::Compaction, &corrupted);
+        let err = engine.get("resume_a").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WiscError>(),
+            Some(WiscError::EngineFailed(_))
+        ));
+        assert!(engine.resume().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn flush_failure_resume_test() -> Result<()> {
+        let root = env::temp_dir()
+            .join("r_wisckey")
+            .join("flush_failure_resume_test");
+        let _ = remove_dir_all(&root);
+        let value = "v".repeat(512);
+        {
+            let mut engine = LsmLogEngine::open_at(&root)?;
+            // level-0 目录被同名的普通文件占用，flush 无法创建 SSTable
+            let level_0 = LevelDir::new(&engine.data_dir, 0).to_path()?;
+            remove_dir_all(&level_0)?;
+            fs::write(&level_0, b"")?;
+
+            // 写满一个内存表，切换之后后台 flush 失败
+            let immutables = engine.mem_tables.read().unwrap().immutables();
+            let mut written = 0;
+            while immutables.is_empty() {
+                // 切换内存表的那次写入可能已经看到了 flush 失败
+                if engine
+                    .set(&format!("resume_key_{:05}", written), &value)
+                    .is_ok()
+                {
+                    written += 1;
+                }
+            }
+            engine.wait_idle();
+            match engine.state() {
+                EngineState::ReadOnly(err) => assert_eq!(err.source, ErrorSource::Flush),
+                state => panic!("unexpected state: {}", state),
+            }
+
+            // 只读模式下依然可以读取不可变内存表中的数据，写入被拒绝
+            assert_eq!(engine.get("resume_key_00000")?, Some(value.clone()));
+            let err = engine.set("resume_rejected", "v1").unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<WiscError>(),
+                Some(WiscError::ReadOnly(_))
+            ));
+            // 原因没有修复时 resume 失败，依然保持只读
+            assert!(engine.resume().is_err());
+            assert!(matches!(engine.state(), EngineState::ReadOnly(_)));
+
+            // 修复之后 resume 重新 flush 等待中的不可变内存表，之后恢复写入
+            remove_file(&level_0)?;
+            engine.resume()?;
+            assert_eq!(engine.state(), EngineState::Normal);
+            assert!(immutables.is_empty());
+            assert_eq!(engine.current_version().files(0).len(), 1);
+            engine.set("resume_after", "v1")?;
+            assert_eq!(
+                engine.get(&format!("resume_key_{:05}", written - 1))?,
+                Some(value.clone())
+            );
+        }
+        let engine = LsmLogEngine::open_at(&root)?;
+        assert_eq!(engine.get("resume_key_00000")?, Some(value));
+        assert_eq!(engine.get("resume_after")?, Some("v1".to_string()));
+        assert_eq!(engine.get("resume_rejected")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn recover_test() -> Result<()> {
+        let root = env::temp_dir().join("r_wisckey").join("recover_test");
+        let _ = remove_dir_all(&root);
+        {
+            let mut engine = LsmLogEngine::open_at(&root)?;
+            engine.set("recover_a", "v1")?;
+            engine.set("recover_a", "v2")?;
+            engine.set("recover_b", "v1")?;
+            engine.set("recover_c", &"v".repeat(SERVER_CONFIG.value_threshold))?;
+            engine.remove("recover_b")?;
+        }
+        let mut engine = LsmLogEngine::open_at(&root)?;
+        assert_eq!(engine.get("recover_a")?, Some("v2".to_string()));
+        assert_eq!(engine.get("recover_b")?, None);
+        assert_eq!(
+            engine.get("recover_c")?,
+            Some("v".repeat(SERVER_CONFIG.value_threshold))
+        );
+        // 恢复之后可以继续写入
+        engine.set("recover_b", "v2")?;
+        assert_eq!(engine.get("recover_b")?, Some("v2".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn group_commit_write_test() -> Result<()> {
+        let engine = open_temp("group_commit_write_test")?;
+        engine
+            .wal_writer
+            .lock()
+            .unwrap()
+            .set_sync_mode(WalSyncMode::GroupCommit);
+        let engine = Arc::new(engine);
+        let (threads, writes) = (8, 50);
+        let handles: Vec<_> = (0..threads)
+            .map(|thread| {
+                let engine = engine.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..writes {
+                        let mut batch = WriteBatch::new();
+                        batch.put(format!("group_{}_{}", thread, i), "v");
+                        engine.write(batch)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        // 同时等待的写入共享 fsync
+        let syncs = engine.statistics().wal_syncs;
+        assert!(syncs > 0 && syncs < (threads * writes) as u64);
+        for thread in 0..threads {
+            for i in 0..writes {
+                let key = format!("group_{}_{}", thread, i);
+                assert_eq!(engine.get(&key)?, Some("v".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn write_batch_test() -> Result<()> {
+        let root = env::temp_dir().join("r_wisckey").join("write_batch_test");
+        let _ = remove_dir_all(&root);
+        let large = "v".repeat(SERVER_CONFIG.value_threshold);
+        {
+            let mut engine = LsmLogEngine::open_at(&root)?;
+            engine.set("batch_c", "v1")?;
+            let mut batch = WriteBatch::new();
+            batch
+                .put("batch_a", "v1")
+                .put("batch_b", &large)
+                .delete("batch_c")
+                .put("batch_a", "v2");
+            engine.write_batch(batch)?;
+            assert_eq!(engine.get("batch_a")?, Some("v2".to_string()));
+            assert_eq!(engine.get("batch_b")?, Some(large.clone()));
+            assert_eq!(engine.get("batch_c")?, None);
+        }
+        // batch 整体从 WAL 中恢复
+        let engine = LsmLogEngine::open_at(&root)?;
+        assert_eq!(engine.get("batch_a")?, Some("v2".to_string()));
+        assert_eq!(engine.get("batch_b")?, Some(large));
+        assert_eq!(engine.get("batch_c")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_test() -> Result<()> {
+        let mut engine = open_temp("snapshot_test")?;
+        let large = "v".repeat(SERVER_CONFIG.value_threshold);
+        engine.set("snapshot_a", "v1")?;
+        engine.set("snapshot_b", &large)?;
+        let snapshot = engine.snapshot();
+        engine.set("snapshot_a", "v2")?;
+        engine.set("snapshot_b", "v2")?;
+        engine.set("snapshot_c", "v1")?;
+
+        assert_eq!(engine.get("snapshot_a")?, Some("v2".to_string()));
+        assert_eq!(
+            engine.get_at(&snapshot, b"snapshot_a")?,
+            Some(b"v1".to_vec())
+        );
+        assert_eq!(engine.get_at(&snapshot, b"snapshot_c")?, None);
+        // 快照存活期间 vLog 不会回收快照仍然需要的 value
+        engine.gc()?;
+        assert_eq!(
+            engine.get_at(&snapshot, b"snapshot_b")?,
+            Some(large.into_bytes())
+        );
+
+        engine.remove("snapshot_a")?;
+        let range = Scans::new(
+            Bound::Included(b"snapshot_".to_vec()),
+            Bound::Unbounded,
+            Some(1),
+        );
+        assert_eq!(
+            engine.scan_at(&snapshot, range.clone())?,
+            vec![(b"snapshot_a".to_vec(), b"v1".to_vec())]
+        );
+        assert_eq!(
+            engine.scan_bytes(range)?,
+            vec![(b"snapshot_b".to_vec(), b"v2".to_vec())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bytes_test() -> Result<()> {
+        let root = env::temp_dir().join("r_wisckey").join("bytes_test");
+        let _ = remove_dir_all(&root);
+        let key = [0xff, 0x00, 0xfe];
+        let large = vec![0x80; SERVER_CONFIG.value_threshold];
+        {
+            let mut engine = LsmLogEngine::open_at(&root)?;
+            engine.set_bytes(&key, &[0x00, 0xc3, 0x28])?;
+            engine.set_bytes(&[0xff], &large)?;
+            assert_eq!(engine.get_bytes(&key)?, Some(vec![0x00, 0xc3, 0x28]));
+            // 不是合法 UTF-8 的数据不能通过字符串接口读取
+            assert!(engine
+                .scan(Scans::new(Bound::Unbounded, Bound::Unbounded, None))
+                .is_err());
+        }
+        // 任意字节的 key / value 经过 WAL 和 vLog 恢复
+        let mut engine = LsmLogEngine::open_at(&root)?;
+        assert_eq!(
+            engine.scan_bytes(Scans::new(Bound::Unbounded, Bound::Unbounded, None))?,
+            vec![(vec![0xff], large), (key.to_vec(), vec![0x00, 0xc3, 0x28])]
+        );
+        engine.remove_bytes(&key)?;
+        assert_eq!(engine.get_bytes(&key)?, None);
+        Ok(())
+    }
+
+    /// 用户 key 降序排列的比较器
+    #[derive(Debug)]
+    struct ReverseComparator;
+    impl Comparator for ReverseComparator {
+        fn name(&self) -> &str {
+            "test.ReverseComparator"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn comparator_test() -> Result<()> {
+        let root = env::temp_dir().join("r_wisckey").join("comparator_test");
+        let _ = remove_dir_all(&root);
+        {
+            let mut engine =
+                LsmLogEngine::open_with_comparator(&root, Arc::new(ReverseComparator))?;
+            for key in ["b", "a", "c"] {
+                engine.set(key, key)?;
+            }
+            engine.set("b", "b2")?;
+            let range = Scans::new(Bound::Unbounded, Bound::Excluded(b"a".to_vec()), None);
+            assert_eq!(
+                engine.scan(range)?,
+                vec![
+                    ("c".to_string(), "c".to_string()),
+                    ("b".to_string(), "b2".to_string()),
+                ]
+            );
+        }
+        // 数据目录与创建时的比较器绑定
+        let err = LsmLogEngine::open_at(&root).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WiscError>(),
+            Some(WiscError::ComparatorMismatch(_, _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn recover_torn_tail_test() -> Result<()> {
+        let root = env::temp_dir()
+            .join("r_wisckey")
+            .join("recover_torn_tail_test");
+        let _ = remove_dir_all(&root);
+        let wal_dir = root.join(&SERVER_CONFIG.wal_dir);
+        {
+            let mut engine = LsmLogEngine::open_at(&root)?;
+            engine.set("torn_a", "v1")?;
+            engine.set("torn_b", "v1")?;
+        }
+        // 模拟写入最后一条 record 时崩溃
+        let (_, log_path) = log_files(&wal_dir)?.pop().unwrap();
+        let log_len = log_path.metadata()?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)?
+            .set_len(log_len - 3)?;
+
+        let engine = LsmLogEngine::open_at(&root)?;
+        assert_eq!(engine.wal_dropped_ranges().len(), 1);
+        assert_eq!(engine.wal_dropped_ranges()[0].end, log_len - 3);
+        assert_eq!(engine.get("torn_a")?, Some("v1".to_string()));
+        assert_eq!(engine.get("torn_b")?, None);
+        // 恢复的数据已经 flush，损坏的 log 文件被删除
+        assert!(!log_path.exists());
+        assert_eq!(engine.current_version().files(0).len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_test() -> Result<()> {
+        let mut engine = open_temp("scan_test")?;
+        for key in ["scan_test_a", "scan_test_b", "scan_test_c", "scan_test_d"] {
+            engine.set(key, key)?;
+        }
+        engine.set("scan_test_b", "new")?;
+        engine.remove("scan_test_c")?;
+
+        let range = Scans::new(
+            Bound::Included(b"scan_test_a".to_vec()),
+            Bound::Excluded(b"scan_test_d".to_vec()),
+            None,
+        );
+        assert_eq!(
+            engine.scan(range)?,
+            vec![
+                ("scan_test_a".to_string(), "scan_test_a".to_string()),
+                ("scan_test_b".to_string(), "new".to_string()),
+            ]
+        );
+
+        let range = Scans::new(
+            Bound::Excluded(b"scan_test_a".to_vec()),
+            Bound::Unbounded,
+            Some(1),
+        );
+        assert_eq!(
+            engine.scan(range)?,
+            vec![("scan_test_b".to_string(), "new".to_string())]
+        );
+        Ok(())
+    }
 
     #[test]
     fn test_01() -> Result<()> {
@@ -2,147 +2,373 @@
 
 #![allow(dead_code)]
 
+use crossbeam_skiplist::map::Iter;
 use crossbeam_skiplist::SkipMap;
-use std::sync::Arc;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
+use crate::common::types::ByteVec;
+use crate::config::SERVER_CONFIG;
+use crate::engines::lsm_log_engine::comparator::{
+    seek_key, Comparator, InternalKeyComparator, MAX_SEQUENCE,
+};
 use crate::engines::lsm_log_engine::wal_log::Key;
+use crate::engines::lsm_log_engine::write_stall::{StallCause, WriteStall};
+use crate::engines::Scans;
+
+/// 内存表中的 key：编码之后的 internal key，按照 `InternalKeyComparator` 排序
+#[derive(Debug, Clone)]
+pub struct MemKey {
+    internal_key: ByteVec,
+    comparator: Arc<InternalKeyComparator>,
+}
+impl MemKey {
+    pub fn new(internal_key: ByteVec, comparator: Arc<InternalKeyComparator>) -> Self {
+        MemKey {
+            internal_key,
+            comparator,
+        }
+    }
+}
+impl PartialEq for MemKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for MemKey {}
+impl PartialOrd for MemKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for MemKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.comparator
+            .compare(&self.internal_key, &other.internal_key)
+    }
+}
+
+/// 内存表的跳表，按照（用户 key 升序，sequence 降序）排列
+pub type Table = SkipMap<MemKey, Key>;
+
+/// 跳表中每条数据除 key 和 value 之外的额外开销（节点的指针、比较器的引用等），用于估算内存占用
+const ENTRY_OVERHEAD: usize = 64;
 
 /// 单个内存表的结构体表示
 #[derive(Debug)]
 pub struct MemTable {
-    pub table: Arc<SkipMap<String, Key>>,
-    /// 是否可变
-    pub status: MemTableStatus,
-    num: u8,
+    table: Table,
+    /// 写入数据的近似内存占用（字节），达到 `write_buffer_size` 时切换内存表
+    memory_usage: AtomicUsize,
+    comparator: Arc<InternalKeyComparator>,
 }
 impl MemTable {
-    /// 将当前的table 标记为不可变, 返回当前操作的table的标号
-    pub fn mark_imu(&mut self) {
-        self.status = MemTableStatus::Imu;
-    }
-    /// 将当前的table 标记为可变, 返回当前操作的table的标号
-    pub fn mark_mut(&mut self) {
-        self.status = MemTableStatus::Mut;
-    }
-    /// 将当前的table 标记为临时状态, 返回当前操作的table的标号
-    pub fn mark_temp(&mut self) {
-        self.status = MemTableStatus::Temp;
+    pub fn new(comparator: Arc<InternalKeyComparator>) -> Self {
+        MemTable {
+            table: Default::default(),
+            memory_usage: AtomicUsize::new(0),
+            comparator,
+        }
     }
-    #[allow(unused)]
+
     /// 获取当前内存表的数据长度
     pub fn len(&self) -> usize {
         self.table.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// 写入数据的近似内存占用（字节）
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 按照（用户 key 升序，sequence 降序）遍历所有数据
+    pub fn iter(&self) -> Iter<'_, MemKey, Key> {
+        self.table.iter()
+    }
+
+    /// 清空所有数据
+    pub fn clear(&self) {
+        self.table.clear();
+        self.memory_usage.store(0, AtomicOrdering::Relaxed);
+    }
+
+    /// 写入一条数据
+    pub fn insert(&self, key: Key) {
+        let mem_key = MemKey::new(key.internal_key(), self.comparator.clone());
+        self.memory_usage.fetch_add(
+            mem_key.internal_key.len() + key.encoded_len() + ENTRY_OVERHEAD,
+            AtomicOrdering::Relaxed,
+        );
+        self.table.insert(mem_key, key);
+    }
+
+    /// 根据用户 key 获取当前内存表中 sequence 不大于 `sequence` 的最新的 `Key`
+    ///
+    /// 同一个用户 key 的版本按 sequence 降序排列，seek 到的第一个元素即是
+    pub fn get(&self, key: &[u8], sequence: i64) -> Option<Key> {
+        let seek = MemKey::new(seek_key(key, sequence), self.comparator.clone());
+        self.table
+            .lower_bound(Bound::Included(&seek))
+            .filter(|entry| {
+                self.comparator.compare_user_key(entry.value().key(), key) == Ordering::Equal
+            })
+            .map(|entry| entry.value().clone())
+    }
+
+    /// 获取范围内每个用户 key 中 sequence 不大于 `sequence` 的最新版本，按用户 key 排序
+    pub fn scan(&self, range: &Scans, sequence: i64) -> Vec<Key> {
+        let user_comparator = self.comparator.user_comparator();
+        let entries: Box<dyn Iterator<Item = _>> = match &range.start {
+            Bound::Included(start) | Bound::Excluded(start) => {
+                let seek = MemKey::new(seek_key(start, MAX_SEQUENCE), self.comparator.clone());
+                Box::new(self.table.range(seek..))
+            }
+            Bound::Unbounded => Box::new(self.table.iter()),
+        };
+        let mut result: Vec<Key> = Vec::new();
+        for entry in entries {
+            let key = entry.value();
+            if !range.before_end(user_comparator, key.key()) {
+                break;
+            }
+            if key.sequence() > sequence {
+                continue;
+            }
+            // 同一个用户 key 只保留排在最前面的最新版本
+            let is_older = result.last().is_some_and(|newest| {
+                user_comparator.compare(newest.key(), key.key()) == Ordering::Equal
+            });
+            if !is_older && range.after_start(user_comparator, key.key()) {
+                result.push(key.clone());
+            }
+        }
+        result
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum MemTableStatus {
-    Imu,
-    Mut,
-    /// 临时中转状态
-    Temp,
+/// 等待 flush 的不可变内存表
+#[derive(Debug)]
+pub struct ImmutableMemTable {
+    pub table: MemTable,
+    /// 内存表中的数据所在的最后一个 log 文件的编号，
+    /// flush 完成之后编号不大于它的 log 文件中的数据都已经持久化
+    pub log_number: u64,
+}
+
+/// 等待 flush 的不可变内存表队列，由写入线程和 flush 线程共享
+///
+/// flush 线程在数据文件提交到 MANIFEST 之后才会从队列中移除内存表，
+/// 因此读取时总能在队列或者当前 version 中找到其中的数据
+#[derive(Debug, Default)]
+pub struct ImmutableQueue {
+    /// 从旧到新排列
+    tables: RwLock<VecDeque<Arc<ImmutableMemTable>>>,
+    /// 较新的内存表覆盖较旧的数据，log_number 也只能递增，因此必须从旧到新逐个 flush
+    flush_lock: Mutex<()>,
+}
+impl ImmutableQueue {
+    pub fn len(&self) -> usize {
+        self.tables.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 最旧的不可变内存表，即下一个需要 flush 的
+    pub fn oldest(&self) -> Option<Arc<ImmutableMemTable>> {
+        self.tables.read().unwrap().front().cloned()
+    }
+
+    /// flush 提交之后移除最旧的不可变内存表
+    pub fn pop_oldest(&self) {
+        self.tables.write().unwrap().pop_front();
+    }
+
+    /// flush 期间持有，保证同一时刻只有一个线程按顺序 flush
+    pub fn lock_flush(&self) -> MutexGuard<'_, ()> {
+        self.flush_lock.lock().unwrap()
+    }
+
+    fn push(&self, table: Arc<ImmutableMemTable>) {
+        self.tables.write().unwrap().push_back(table);
+    }
+
+    /// 所有的不可变内存表，从新到旧排列
+    fn newest_first(&self) -> Vec<Arc<ImmutableMemTable>> {
+        self.tables.read().unwrap().iter().rev().cloned().collect()
+    }
 }
 
 /// 包含可变和不可变内存表的结构体表示
 ///
-/// 循环地方式使用两个 mem_table
+/// mut_table 的内存占用达到 `write_buffer_size` 时整体移入不可变队列，换上新的空内存表，
+/// 队列中最多保留 `max_immutable_memtables` 个等待 flush 的内存表
 #[derive(Debug)]
 pub struct MemTables {
-    mem_table_01: MemTable,
-    mem_table_02: MemTable,
+    mut_table: MemTable,
+    immutables: Arc<ImmutableQueue>,
+    comparator: Arc<InternalKeyComparator>,
 }
 impl MemTables {
-    pub fn new() -> Self {
+    pub fn new(comparator: Arc<InternalKeyComparator>) -> Self {
         MemTables {
-            mem_table_01: MemTable {
-                table: Default::default(),
-                status: MemTableStatus::Mut,
-                num: 0,
-            },
-            mem_table_02: MemTable {
-                table: Default::default(),
-                status: MemTableStatus::Imu,
-                num: 1,
-            },
+            mut_table: MemTable::new(comparator.clone()),
+            immutables: Arc::default(),
+            comparator,
         }
     }
     /// 获取其中的可变内存表
-    pub fn mut_table(&mut self) -> Option<&mut MemTable> {
-        if self.mem_table_01.status == MemTableStatus::Mut {
-            Some(&mut self.mem_table_01)
-        } else if self.mem_table_02.status == MemTableStatus::Mut {
-            Some(&mut self.mem_table_02)
-        } else {
-            None
-        }
+    pub fn mut_table(&self) -> &MemTable {
+        &self.mut_table
     }
-    /// 获取其中的不可变内存表
-    pub fn imu_table(&mut self) -> Option<&mut MemTable> {
-        if self.mem_table_01.status == MemTableStatus::Imu {
-            Some(&mut self.mem_table_01)
-        } else if self.mem_table_02.status == MemTableStatus::Imu {
-            Some(&mut self.mem_table_02)
-        } else {
-            None
-        }
+    /// 等待 flush 的不可变内存表队列
+    pub fn immutables(&self) -> Arc<ImmutableQueue> {
+        self.immutables.clone()
+    }
+    /// mut_table 的内存占用是否已经达到 `write_buffer_size`
+    pub fn should_switch(&self) -> bool {
+        self.mut_table.memory_usage() >= SERVER_CONFIG.write_buffer_size
     }
-    /// 获取其中的临时状态内存表
+    /// 按照 mut_table -> 不可变内存表（从新到旧）的顺序查找用户 key 中 sequence 不大于 `sequence` 的最新版本
     ///
-    /// 注意调用此方法的时候需要确保上文中进行过 `mark_temp` 操作
-    pub fn temp_table(&mut self) -> Option<&mut MemTable> {
-        match self.mem_table_01.status {
-            MemTableStatus::Temp => Some(&mut self.mem_table_01),
-            _ => Some(&mut self.mem_table_02),
+    /// 越新的内存表中的数据越新，因此先找到的即是最新的
+    pub fn get(&self, key: &[u8], sequence: i64) -> Option<Key> {
+        self.mut_table.get(key, sequence).or_else(|| {
+            self.immutables
+                .newest_first()
+                .iter()
+                .find_map(|immutable| immutable.table.get(key, sequence))
+        })
+    }
+    /// 范围查询，返回 mut_table 和每个不可变内存表各自的结果
+    pub fn scan(&self, range: &Scans, sequence: i64) -> Vec<Vec<Key>> {
+        let mut result = vec![self.mut_table.scan(range, sequence)];
+        for immutable in self.immutables.newest_first() {
+            result.push(immutable.table.scan(range, sequence));
         }
+        result
     }
     /// 写入memtable
-    pub fn add_record(&mut self, key: &Key) {
-        loop {
-            if self.mut_table().is_some() {
-                break;
-            }
-        }
-        self.mut_table()
-            .unwrap()
-            .table
-            .insert(key.get_sort_key(), key.clone());
+    pub fn add_record(&self, key: &Key) {
+        self.mut_table.insert(key.clone());
     }
 
-    /// 调换两个table的状态
+    /// 不可变队列已满时阻塞，直到 flush 线程移除最旧的内存表之后通过 `stall` 唤醒
     ///
-    /// 可能会阻塞
-    pub fn exchange(&mut self) {
-        // 需要将当前的 mut_table 变为 imu,等待 minor;
-        // 然后将原来的 imu_table 变为 mut_table,接收新的写入
-        // 交换之前要判断
-        // 如果 当前 imu_table 的长度不为0，表示上次满了之后，刷盘动作为完成，阻塞用户的当前操作，
-        // 直到 imu_table 的长度为0
-        loop {
-            // 如果当前 imu_table 没有flush完成，
-            // 此时的 mut_table 又是满的（必定是满的，因为log切换了新文件才会执行 exchange），
-            // 那么这时交换是需要阻塞的，
-            // 等imu_table flush 结束之后才会交换状态
-            if let Some(imu_table) = self.imu_table() {
-                if imu_table.table.is_empty() {
-                    break;
-                }
-            }
-        }
-        // 中间状态只会存在于这段代码中，
-        // 因此 接下来的状态的table都必定有值的
-        self.mut_table().unwrap().mark_temp(); // 临时状态
-                                               // 上面的阻塞确保了 imu_table-》转换的 mut_table 是可以接受新数据的
-        self.imu_table().unwrap().mark_mut();
-        self.temp_table().unwrap().mark_imu();
+    /// 只需要共享引用，等待期间不影响读取
+    pub fn wait_for_room(&self, stall: &WriteStall) {
+        let max_immutables = SERVER_CONFIG.max_immutable_memtables.max(1);
+        stall.wait_while(StallCause::MemtableFull, || {
+            self.immutables.len() >= max_immutables
+        });
+    }
+
+    /// 将 mut_table 移入不可变队列并换上新的空内存表，`log_number` 为其数据所在的最后一个 log 文件
+    ///
+    /// 队列已满时阻塞，flush 线程移除最旧的内存表之后通过 `stall` 唤醒
+    pub fn switch(&mut self, log_number: u64, stall: &WriteStall) {
+        self.wait_for_room(stall);
+        let table = std::mem::replace(&mut self.mut_table, MemTable::new(self.comparator.clone()));
+        self.immutables
+            .push(Arc::new(ImmutableMemTable { table, log_number }));
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::engines::lsm_log_engine::wal_log::DataType;
+
     #[test]
     fn test() {
-        let a = MemTables::new();
+        let a = MemTables::new(Arc::default());
         println!("{:?}", a);
     }
+
+    #[test]
+    fn get_test() {
+        let mut tables = MemTables::new(Arc::default());
+        let first = Key::new("a", "1", DataType::Set);
+        tables.add_record(&first);
+        tables.add_record(&Key::new("a-b", "x", DataType::Set));
+        tables.add_record(&Key::new("a", "2", DataType::Set));
+
+        assert_eq!(tables.get(b"a", MAX_SEQUENCE).unwrap().value(), b"2");
+        assert_eq!(tables.get(b"a-b", MAX_SEQUENCE).unwrap().value(), b"x");
+        assert!(tables.get(b"b", MAX_SEQUENCE).is_none());
+        // 按照 sequence 读取时看不到之后写入的版本
+        assert_eq!(tables.get(b"a", first.sequence()).unwrap().value(), b"1");
+        assert!(tables.get(b"a", first.sequence() - 1).is_none());
+
+        // 切换之后旧数据位于不可变内存表中，依然可以读到，新的写入覆盖旧的数据
+        let stall = WriteStall::default();
+        tables.switch(1, &stall);
+        assert!(tables.mut_table().is_empty());
+        tables.add_record(&Key::new("a-b", "y", DataType::Set));
+        tables.switch(2, &stall);
+        assert_eq!(tables.immutables().len(), 2);
+        assert_eq!(tables.get(b"a", MAX_SEQUENCE).unwrap().value(), b"2");
+        assert_eq!(tables.get(b"a-b", MAX_SEQUENCE).unwrap().value(), b"y");
+
+        // flush 从最旧的内存表开始
+        let immutables = tables.immutables();
+        assert_eq!(immutables.oldest().unwrap().log_number, 1);
+        immutables.pop_oldest();
+        assert!(tables.get(b"a", MAX_SEQUENCE).is_none());
+        assert_eq!(tables.get(b"a-b", MAX_SEQUENCE).unwrap().value(), b"y");
+    }
+
+    #[test]
+    fn memory_usage_test() {
+        let table = MemTable::new(Arc::default());
+        assert_eq!(table.memory_usage(), 0);
+        let key = Key::new("a", "v".repeat(1024), DataType::Set);
+        table.insert(key.clone());
+        assert!(table.memory_usage() > key.encoded_len());
+        let usage = table.memory_usage();
+        table.insert(Key::new("b", "v".repeat(1024), DataType::Set));
+        assert!(table.memory_usage() >= usage * 2);
+        table.clear();
+        assert_eq!((table.len(), table.memory_usage()), (0, 0));
+    }
+
+    #[test]
+    fn scan_test() {
+        let tables = MemTables::new(Arc::default());
+        for (key, value) in [
+            ("a1", "1"),
+            ("a", "1"),
+            ("a-1", "1"),
+            ("a", "2"),
+            ("b", "1"),
+        ] {
+            tables.add_record(&Key::new(key, value, DataType::Set));
+        }
+        let range = Scans::new(
+            Bound::Included(b"a".to_vec()),
+            Bound::Excluded(b"b".to_vec()),
+            None,
+        );
+        let scanned = tables.scan(&range, MAX_SEQUENCE);
+        let keys: Vec<(&[u8], &[u8])> = scanned[0]
+            .iter()
+            .map(|key| (key.key(), key.value()))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (&b"a"[..], &b"2"[..]),
+                (&b"a-1"[..], &b"1"[..]),
+                (&b"a1"[..], &b"1"[..]),
+            ]
+        );
+    }
 }
@@ -1,4 +1,17 @@
+pub mod cache;
+pub mod compaction;
+pub mod comparator;
+pub mod compression;
+pub mod error_handler;
+pub mod filter;
+pub mod iterator;
 pub mod level;
 pub mod lsm_engine;
 pub mod mem;
+pub mod scheduler;
+pub mod snapshot;
+pub mod sstable;
+pub mod version;
+pub mod vlog;
 pub mod wal_log;
+pub mod write_stall;
@@ -0,0 +1,380 @@
+//! 后台任务调度
+//!
+//! flush 和 compaction 分别在各自固定数量的线程中执行，线程数由 `max_background_flushes`、
+//! `max_background_compactions` 配置。每个线程池有一个按照优先级排列的队列，优先级相同时先提交的先执行。
+//!
+//! 任务返回的错误记录在 `ErrorHandler` 中，存储引擎随之切换为只读模式；
+//! 同时唤醒所有阻塞的写入，避免它们一直等待一个已经失败的 flush
+
+use anyhow::Result;
+use log::error;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::common::error_enum::WiscError;
+use crate::engines::lsm_log_engine::error_handler::{ErrorHandler, ErrorSource};
+
+/// flush 线程名前缀
+pub const FLUSH_THREAD: &str = "flush-thread";
+/// compaction 线程名前缀
+pub const COMPACTION_THREAD: &str = "compaction-thread";
+
+/// 后台任务的种类，决定任务在哪个线程池中执行
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    Flush,
+    Compaction,
+}
+
+/// 同一个线程池中任务的优先级
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JobPriority {
+    Low,
+    High,
+}
+
+/// 关闭调度器的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// 执行完队列中的所有任务，包括它们提交的后续任务
+    Wait,
+    /// 丢弃队列中尚未开始的任务，正在执行的任务在下一个检查点退出
+    Cancel,
+}
+
+/// 后台任务的统计信息
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SchedulerStatistics {
+    pub queued_flushes: usize,
+    pub queued_compactions: usize,
+    pub running: usize,
+    pub completed: u64,
+    pub failed: u64,
+}
+
+type Job = Box<dyn FnOnce(&JobContext) -> Result<()> + Send>;
+
+struct QueuedJob {
+    priority: JobPriority,
+    /// 提交的顺序
+    id: u64,
+    job: Job,
+}
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for QueuedJob {}
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for QueuedJob {
+    /// `BinaryHeap` 先弹出最大的：优先级高的、优先级相同时先提交的
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.id.cmp(&self.id))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    flushes: BinaryHeap<QueuedJob>,
+    compactions: BinaryHeap<QueuedJob>,
+    /// 正在执行的任务个数
+    running: usize,
+    next_id: u64,
+    /// 开始关闭之后不再接受外部提交的任务
+    shutting_down: bool,
+}
+impl State {
+    fn queue(&mut self, kind: JobKind) -> &mut BinaryHeap<QueuedJob> {
+        match kind {
+            JobKind::Flush => &mut self.flushes,
+            JobKind::Compaction => &mut self.compactions,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.running == 0 && self.flushes.is_empty() && self.compactions.is_empty()
+    }
+}
+
+/// 调度器和工作线程共享的状态
+struct Shared {
+    state: Mutex<State>,
+    /// 有新的任务、任务完成或者开始关闭时唤醒工作线程和等待空闲的线程
+    changed: Condvar,
+    cancelled: AtomicBool,
+    errors: Arc<ErrorHandler>,
+    completed: AtomicU64,
+    failed: AtomicU64,
+}
+impl Shared {
+    fn push(&self, kind: JobKind, priority: JobPriority, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue(kind).push(QueuedJob { priority, id, job });
+        self.changed.notify_all();
+    }
+
+    fn record_error(&self, kind: JobKind, err: anyhow::Error) {
+        self.failed.fetch_add(1, AtomicOrdering::Relaxed);
+        let source = match kind {
+            JobKind::Flush => ErrorSource::Flush,
+            JobKind::Compaction => ErrorSource::Compaction,
+        };
+        self.errors.record(source, &err);
+    }
+}
+
+/// 任务执行时的上下文
+pub struct JobContext<'a> {
+    shared: &'a Shared,
+}
+impl JobContext<'_> {
+    /// 调度器是否已经以 `ShutdownMode::Cancel` 关闭，耗时较长的任务应当在检查点提前退出
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(AtomicOrdering::SeqCst)
+    }
+
+    /// 提交后续任务，例如 flush 之后检查是否需要 compaction
+    ///
+    /// `ShutdownMode::Wait` 关闭期间依然可以提交，取消之后提交的任务直接丢弃
+    pub fn schedule(
+        &self,
+        kind: JobKind,
+        priority: JobPriority,
+        job: impl FnOnce(&JobContext) -> Result<()> + Send + 'static,
+    ) {
+        if !self.is_cancelled() {
+            self.shared.push(kind, priority, Box::new(job));
+        }
+    }
+}
+
+/// 后台 flush 和 compaction 的线程池
+pub struct BackgroundScheduler {
+    shared: Arc<Shared>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+impl fmt::Debug for BackgroundScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackgroundScheduler")
+            .field("statistics", &self.statistics())
+            .finish()
+    }
+}
+impl BackgroundScheduler {
+    /// 启动 `flush_threads` 个 flush 线程和 `compaction_threads` 个 compaction 线程，各自至少 1 个
+    ///
+    /// 任务失败时记录在 `errors` 中
+    pub fn new(
+        flush_threads: usize,
+        compaction_threads: usize,
+        errors: Arc<ErrorHandler>,
+    ) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::default(),
+            changed: Condvar::new(),
+            cancelled: AtomicBool::new(false),
+            errors,
+            completed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        });
+        let mut threads = Vec::new();
+        for (kind, name, count) in [
+            (JobKind::Flush, FLUSH_THREAD, flush_threads),
+            (JobKind::Compaction, COMPACTION_THREAD, compaction_threads),
+        ] {
+            for index in 0..count.max(1) {
+                let shared = shared.clone();
+                threads.push(
+                    thread::Builder::new()
+                        .name(format!("{}-{}", name, index))
+                        .spawn(move || work(&shared, kind))?,
+                );
+            }
+        }
+        Ok(BackgroundScheduler {
+            shared,
+            threads: Mutex::new(threads),
+        })
+    }
+
+    /// 提交任务，调度器关闭之后返回 `WiscError::ShutDown`
+    pub fn schedule(
+        &self,
+        kind: JobKind,
+        priority: JobPriority,
+        job: impl FnOnce(&JobContext) -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        if self.is_shut_down() {
+            return Err(anyhow::Error::from(WiscError::ShutDown));
+        }
+        self.shared.push(kind, priority, Box::new(job));
+        Ok(())
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shared.state.lock().unwrap().shutting_down
+    }
+
+    /// 阻塞直到队列为空并且没有正在执行的任务
+    pub fn wait_idle(&self) {
+        let state = self.shared.state.lock().unwrap();
+        let _state = self
+            .shared
+            .changed
+            .wait_while(state, |state| !state.is_idle())
+            .unwrap();
+    }
+
+    /// 关闭调度器并等待所有线程退出，重复调用时直接返回
+    pub fn shutdown(&self, mode: ShutdownMode) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.shutting_down = true;
+            if mode == ShutdownMode::Cancel {
+                self.shared.cancelled.store(true, AtomicOrdering::SeqCst);
+                state.flushes.clear();
+                state.compactions.clear();
+            }
+            self.shared.changed.notify_all();
+        }
+        let threads: Vec<JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("后台线程异常退出");
+            }
+        }
+    }
+
+    pub fn statistics(&self) -> SchedulerStatistics {
+        let state = self.shared.state.lock().unwrap();
+        SchedulerStatistics {
+            queued_flushes: state.flushes.len(),
+            queued_compactions: state.compactions.len(),
+            running: state.running,
+            completed: self.shared.completed.load(AtomicOrdering::Relaxed),
+            failed: self.shared.failed.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+impl Drop for BackgroundScheduler {
+    fn drop(&mut self) {
+        self.shutdown(ShutdownMode::Cancel);
+    }
+}
+
+/// 工作线程：从 `kind` 对应的队列中取出任务执行
+///
+/// 关闭之后，等到队列为空并且其他线程中没有可能提交后续任务的任务在执行时退出
+fn work(shared: &Shared, kind: JobKind) {
+    loop {
+        let job = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some(job) = state.queue(kind).pop() {
+                    state.running += 1;
+                    break job;
+                }
+                if state.shutting_down && state.running == 0 {
+                    return;
+                }
+                state = shared.changed.wait(state).unwrap();
+            }
+        };
+        match (job.job)(&JobContext { shared }) {
+            Ok(()) => {
+                shared.completed.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+            Err(err) => shared.record_error(kind, err),
+        }
+        shared.state.lock().unwrap().running -= 1;
+        shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engines::lsm_log_engine::error_handler::ErrorSeverity;
+    use std::sync::mpsc;
+
+    #[test]
+    fn priority_test() -> Result<()> {
+        let scheduler = BackgroundScheduler::new(1, 1, Arc::default())?;
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        // 第一个任务占住唯一的 compaction 线程，之后的任务都在队列中排队
+        scheduler.schedule(JobKind::Compaction, JobPriority::Low, move |_| {
+            started_tx.send(())?;
+            release_rx.recv()?;
+            Ok(())
+        })?;
+        started_rx.recv()?;
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for (name, priority) in [
+            ("low-1", JobPriority::Low),
+            ("high-1", JobPriority::High),
+            ("low-2", JobPriority::Low),
+            ("high-2", JobPriority::High),
+        ] {
+            let order = order.clone();
+            scheduler.schedule(JobKind::Compaction, priority, move |_| {
+                order.lock().unwrap().push(name);
+                Ok(())
+            })?;
+        }
+        assert_eq!(scheduler.statistics().queued_compactions, 4);
+        release_tx.send(())?;
+        scheduler.wait_idle();
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["high-1", "high-2", "low-1", "low-2"]
+        );
+        assert_eq!(scheduler.statistics().completed, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn error_and_shutdown_test() -> Result<()> {
+        let errors = Arc::new(ErrorHandler::default());
+        let scheduler = BackgroundScheduler::new(1, 1, errors.clone())?;
+        scheduler.schedule(JobKind::Flush, JobPriority::High, |job| {
+            // 失败之前提交的后续任务依然会执行
+            job.schedule(JobKind::Compaction, JobPriority::Low, |_| Ok(()));
+            Err(anyhow::Error::from(WiscError::KeyNotExist(
+                "flush".to_string(),
+            )))
+        })?;
+        scheduler.wait_idle();
+        let err = errors.error().unwrap();
+        assert_eq!(
+            (err.severity, err.source),
+            (ErrorSeverity::Hard, ErrorSource::Flush)
+        );
+        let statistics = scheduler.statistics();
+        assert_eq!((statistics.completed, statistics.failed), (1, 1));
+
+        scheduler.shutdown(ShutdownMode::Wait);
+        let err = scheduler
+            .schedule(JobKind::Flush, JobPriority::High, |_| Ok(()))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WiscError>(),
+            Some(WiscError::ShutDown)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+//! 快照
+//!
+//! 快照只是一个 sequence，通过快照读取时忽略 sequence 大于它的所有版本。
+//! 存活的快照登记在 `SnapshotList` 中，compaction 会保留对任意存活快照可见的版本
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::common::fn_util::last_sequence;
+
+/// 固定在某个 sequence 上的只读视图，drop 之后释放
+#[derive(Debug)]
+pub struct Snapshot {
+    sequence: i64,
+    list: Arc<SnapshotList>,
+}
+impl Snapshot {
+    /// 快照可见的最大 sequence
+    pub fn sequence(&self) -> i64 {
+        self.sequence
+    }
+}
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.sequence);
+    }
+}
+
+/// 所有存活的快照，同一个 sequence 上可能存在多个快照，记录引用计数
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    sequences: Mutex<BTreeMap<i64, usize>>,
+}
+impl SnapshotList {
+    /// 在最近一次写入的 sequence 上创建快照
+    pub fn acquire(list: &Arc<SnapshotList>) -> Snapshot {
+        // 读取 sequence 和登记在同一次加锁中完成，否则 compaction 可能在两者之间读取快照列表，丢弃对这个快照可见的版本
+        let mut sequences = list.sequences.lock().unwrap();
+        let sequence = last_sequence();
+        *sequences.entry(sequence).or_insert(0) += 1;
+        Snapshot {
+            sequence,
+            list: list.clone(),
+        }
+    }
+
+    fn release(&self, sequence: i64) {
+        let mut sequences = self.sequences.lock().unwrap();
+        if let Some(count) = sequences.get_mut(&sequence) {
+            *count -= 1;
+            if *count == 0 {
+                sequences.remove(&sequence);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sequences.lock().unwrap().is_empty()
+    }
+
+    /// 所有存活快照的 sequence，升序排列
+    pub fn sequences(&self) -> Vec<i64> {
+        self.sequences.lock().unwrap().keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::fn_util::gen_sequence;
+
+    #[test]
+    fn snapshot_list_test() {
+        let list = Arc::new(SnapshotList::default());
+        let first = SnapshotList::acquire(&list);
+        let same = SnapshotList::acquire(&list);
+        gen_sequence();
+        let second = SnapshotList::acquire(&list);
+        assert!(second.sequence() > first.sequence());
+        assert_eq!(list.sequences(), [first.sequence(), second.sequence()]);
+
+        drop(first);
+        assert_eq!(list.sequences(), [same.sequence(), second.sequence()]);
+        drop(same);
+        assert_eq!(list.sequences(), [second.sequence()]);
+        drop(second);
+        assert!(list.is_empty());
+    }
+}
@@ -0,0 +1,640 @@
+//! SSTable 文件格式
+//!
+//! ```text
+//! +----------------+----------------+-----+--------------+-------------+--------+
+//! | data block 0   | data block 1   | ... | filter block | index block | footer |
+//! +----------------+----------------+-----+--------------+-------------+--------+
+//! ```
+//!
+//! - data block：按照 internal key 升序（用户 key 升序，sequence 降序）排列的 `Key::encode` 字节
+//! - filter block：文件中所有用户 key 的布隆过滤器（格式见 `filter` 模块），`bloom_bits_per_key` 为 0 时内容为空
+//! - index block：每个 data block 的最大 internal key 及其位置（bincode 编码）
+//! - 每个 block 之后都有 5 字节的 trailer：压缩方式(1) + crc32(4)，crc32 覆盖压缩之后的内容和压缩方式。
+//!   data block 和 index block 按照所在层级配置的方式压缩，filter block 不压缩
+//! - footer：filter_offset(8) + filter_size(8) + index_offset(8) + index_size(8) + version(4) + magic(8)，固定 44 字节
+
+#![allow(dead_code)]
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::common::error_enum::WiscError;
+use crate::common::fn_util::{checksum, sync_dir};
+use crate::common::types::ByteVec;
+use crate::config::SERVER_CONFIG;
+use crate::engines::lsm_log_engine::cache::BlockCache;
+use crate::engines::lsm_log_engine::comparator::{
+    seek_key, user_key, Comparator, InternalKeyComparator,
+};
+use crate::engines::lsm_log_engine::compression::{compress, decompress, CompressionType};
+use crate::engines::lsm_log_engine::filter::{BloomFilter, FilterBuilder};
+use crate::engines::lsm_log_engine::level::LevelDir;
+use crate::engines::lsm_log_engine::wal_log::Key;
+use crate::engines::Scans;
+
+/// data block 的目标大小：4 KB
+pub const TABLE_BLOCK_SIZE: usize = 1024 * 4;
+/// block 尾部 trailer 的长度：压缩方式(1) + crc32(4)
+pub const BLOCK_TRAILER_SIZE: usize = 1 + 4;
+/// footer 的固定长度
+pub const FOOTER_SIZE: usize = 8 + 8 + 8 + 8 + 4 + 8;
+/// 文件格式的魔数："wisckey!"
+pub const TABLE_MAGIC: u64 = 0x7769_7363_6b65_7921;
+/// 当前的文件格式版本
+pub const TABLE_FORMAT_VERSION: u32 = 4;
+/// 写入过程中的临时文件扩展名，写完并 fsync 之后才会重命名为正式的数据文件
+pub const TABLE_TEMP_EXTENSION: &str = "tmp";
+
+/// block 在文件中的位置，size 为压缩之后的大小，不包含尾部的 trailer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// index block 中的一条记录
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    /// 对应 data block 中最大的 internal key
+    pub last_key: ByteVec,
+    pub handle: BlockHandle,
+}
+
+/// 文件尾部，用于定位 filter block、index block 并校验文件格式
+#[derive(Debug, PartialEq)]
+pub struct Footer {
+    pub filter_handle: BlockHandle,
+    pub index_handle: BlockHandle,
+    pub version: u32,
+}
+impl Footer {
+    pub fn encode(&self) -> ByteVec {
+        let mut buf = ByteVec::with_capacity(FOOTER_SIZE);
+        buf.extend_from_slice(&self.filter_handle.offset.to_le_bytes());
+        buf.extend_from_slice(&self.filter_handle.size.to_le_bytes());
+        buf.extend_from_slice(&self.index_handle.offset.to_le_bytes());
+        buf.extend_from_slice(&self.index_handle.size.to_le_bytes());
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&TABLE_MAGIC.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(content: &[u8], path: &Path) -> Result<Self> {
+        let invalid = || {
+            anyhow::Error::from(WiscError::TableFormatInvalid(
+                path.to_string_lossy().to_string(),
+            ))
+        };
+        if content.len() != FOOTER_SIZE {
+            return Err(invalid());
+        }
+        let magic = u64::from_le_bytes(content[36..44].try_into()?);
+        let version = u32::from_le_bytes(content[32..36].try_into()?);
+        if magic != TABLE_MAGIC || version != TABLE_FORMAT_VERSION {
+            return Err(invalid());
+        }
+        Ok(Footer {
+            filter_handle: BlockHandle {
+                offset: u64::from_le_bytes(content[0..8].try_into()?),
+                size: u64::from_le_bytes(content[8..16].try_into()?),
+            },
+            index_handle: BlockHandle {
+                offset: u64::from_le_bytes(content[16..24].try_into()?),
+                size: u64::from_le_bytes(content[24..32].try_into()?),
+            },
+            version,
+        })
+    }
+}
+
+/// 写入完成的 SSTable 的概要信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableInfo {
+    pub file_size: u64,
+    /// 最小的用户 key
+    pub smallest: ByteVec,
+    /// 最大的用户 key
+    pub largest: ByteVec,
+    pub smallest_seq: i64,
+    pub largest_seq: i64,
+}
+
+/// SSTable 写入
+///
+/// 调用方必须按照 internal key 升序（用户 key 升序，sequence 降序）的顺序添加 `Key`
+pub struct TableBuilder {
+    writer: BufWriter<File>,
+    /// 写入过程中的临时文件
+    temp_path: PathBuf,
+    /// 最终的数据文件
+    path: PathBuf,
+    /// 当前 data block 的内容
+    block: ByteVec,
+    /// 当前 data block 中最后一个 internal key
+    block_last_key: ByteVec,
+    /// 已经写入文件的长度
+    offset: u64,
+    index: Vec<IndexEntry>,
+    /// 所有用户 key 的布隆过滤器
+    filter: FilterBuilder,
+    /// data block 和 index block 的压缩方式
+    compression: CompressionType,
+    entries: usize,
+    /// 第一个添加的用户 key，也就是最小的 key
+    smallest: Option<ByteVec>,
+    smallest_seq: i64,
+    largest_seq: i64,
+}
+impl TableBuilder {
+    /// data block 和 index block 使用 `compression` 压缩，通常为 `CompressionType::for_level` 的结果
+    pub fn new(path: PathBuf, compression: CompressionType) -> Result<Self> {
+        let temp_path = path.with_extension(TABLE_TEMP_EXTENSION);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        Ok(TableBuilder {
+            writer: BufWriter::new(file),
+            temp_path,
+            path,
+            block: ByteVec::with_capacity(TABLE_BLOCK_SIZE),
+            block_last_key: ByteVec::new(),
+            offset: 0,
+            index: Vec::new(),
+            filter: FilterBuilder::new(SERVER_CONFIG.bloom_bits_per_key),
+            compression,
+            entries: 0,
+            smallest: None,
+            smallest_seq: i64::MAX,
+            largest_seq: i64::MIN,
+        })
+    }
+
+    /// 添加一条数据，当前 data block 达到预定大小时写入文件
+    pub fn add(&mut self, key: &Key) -> Result<()> {
+        if self.smallest.is_none() {
+            self.smallest = Some(key.key().to_vec());
+        }
+        self.smallest_seq = self.smallest_seq.min(key.sequence());
+        self.largest_seq = self.largest_seq.max(key.sequence());
+        self.filter.add(key.key());
+        self.block.append(&mut key.encode());
+        self.block_last_key = key.internal_key();
+        self.entries += 1;
+        if self.block.len() >= TABLE_BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// 已经添加的数据条数
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// 当前已经写入以及缓冲中的数据大小
+    pub fn file_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// 写入 filter block、index block 和 footer，fsync 之后重命名为正式的数据文件
+    pub fn finish(mut self) -> Result<TableInfo> {
+        self.flush_block()?;
+        let filter = std::mem::replace(&mut self.filter, FilterBuilder::new(0)).finish();
+        let filter_handle = self.write_block(&filter, CompressionType::None)?;
+        let largest = self
+            .index
+            .last()
+            .map(|entry| user_key(&entry.last_key).to_vec())
+            .unwrap_or_default();
+        let index_byte = bincode::serialize(&self.index)?;
+        let index_handle = self.write_block(&index_byte, self.compression)?;
+        let footer = Footer {
+            filter_handle,
+            index_handle,
+            version: TABLE_FORMAT_VERSION,
+        };
+        self.writer.write_all(&footer.encode())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        rename(&self.temp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            sync_dir(dir)?;
+        }
+        Ok(TableInfo {
+            file_size: self.offset + FOOTER_SIZE as u64,
+            smallest: self.smallest.unwrap_or_default(),
+            largest,
+            smallest_seq: self.smallest_seq,
+            largest_seq: self.largest_seq,
+        })
+    }
+
+    /// 将当前 data block 写入文件并记录到 index
+    fn flush_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.block);
+        let handle = self.write_block(&block, self.compression)?;
+        self.index.push(IndexEntry {
+            last_key: std::mem::take(&mut self.block_last_key),
+            handle,
+        });
+        Ok(())
+    }
+
+    /// 压缩并写入 block 内容以及尾部的 trailer
+    fn write_block(&mut self, content: &[u8], compression: CompressionType) -> Result<BlockHandle> {
+        let (compression, mut content) = compress(compression, content)?;
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: content.len() as u64,
+        };
+        content.push(compression as u8);
+        self.writer.write_all(&content)?;
+        self.writer.write_all(&checksum(&content).to_le_bytes())?;
+        self.offset += handle.size + BLOCK_TRAILER_SIZE as u64;
+        Ok(handle)
+    }
+}
+
+/// SSTable 读取
+///
+/// 打开时读取 index block 和 filter block，之后一直持有文件句柄，
+/// 设置了 block 缓存时 data block 优先从缓存中读取
+#[derive(Debug)]
+pub struct TableReader {
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<IndexEntry>,
+    filter: BloomFilter,
+    comparator: Arc<InternalKeyComparator>,
+    /// 文件编号以及共享的 block 缓存
+    block_cache: Option<(u64, Arc<BlockCache>)>,
+}
+impl TableReader {
+    /// 打开数据文件，校验 footer 并读取 index block 和 filter block
+    pub fn open(path: PathBuf, comparator: Arc<InternalKeyComparator>) -> Result<Self> {
+        let mut file = File::open(&path)?;
+        let footer = read_footer(&mut file, &path)?;
+
+        let index_byte = read_block(&mut file, footer.index_handle)?;
+        let index = bincode::deserialize::<Vec<IndexEntry>>(&index_byte)?;
+        let filter = BloomFilter::new(read_block(&mut file, footer.filter_handle)?);
+        Ok(TableReader {
+            path,
+            file: Mutex::new(file),
+            index,
+            filter,
+            comparator,
+            block_cache: None,
+        })
+    }
+
+    /// 读取的 data block 缓存在 `block_cache` 中，`number` 为该文件的编号
+    pub fn with_block_cache(mut self, number: u64, block_cache: Arc<BlockCache>) -> Self {
+        self.block_cache = Some((number, block_cache));
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// 返回 false 时文件中一定不存在该用户 key
+    pub fn may_contain(&self, user_key: &[u8]) -> bool {
+        self.filter.may_contain(user_key)
+    }
+
+    /// 查找用户 key 中 sequence 不大于 `sequence` 的最新版本
+    ///
+    /// seek key 排在该用户 key 所有可见的版本之前，第一个不小于它的 internal key 所在的 block 中
+    /// 第一个不小于它的数据即是候选，用户 key 相同则是可见的最新版本
+    pub fn get(&self, key: &[u8], sequence: i64) -> Result<Option<Key>> {
+        let seek = seek_key(key, sequence);
+        let index = self
+            .index
+            .partition_point(|entry| self.comparator.compare(&entry.last_key, &seek).is_lt());
+        let entry = match self.index.get(index) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let found = self
+            .data_block(entry.handle)?
+            .iter()
+            .find(|internal_key| {
+                self.comparator
+                    .compare(&internal_key.internal_key(), &seek)
+                    .is_ge()
+            })
+            .cloned();
+        Ok(found.filter(|internal_key| {
+            self.comparator
+                .compare_user_key(internal_key.key(), key)
+                .is_eq()
+        }))
+    }
+
+    /// 范围查询，返回范围内 sequence 不大于 `sequence` 的所有版本，按照（用户 key 升序，sequence 降序）排列
+    pub fn scan(&self, range: &Scans, sequence: i64) -> Result<Vec<Key>> {
+        let user_comparator = self.comparator.user_comparator();
+        let mut result = Vec::new();
+        for entry in &self.index {
+            // 跳过整个 block 都在起始边界之前的情况
+            if !range.after_start(user_comparator, user_key(&entry.last_key)) {
+                continue;
+            }
+            let mut past_end = false;
+            for internal_key in self.data_block(entry.handle)?.iter() {
+                if !range.before_end(user_comparator, internal_key.key()) {
+                    past_end = true;
+                    break;
+                }
+                if internal_key.sequence() <= sequence
+                    && range.after_start(user_comparator, internal_key.key())
+                {
+                    result.push(internal_key.clone());
+                }
+            }
+            if past_end {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    /// 按顺序读取文件中的所有数据，不经过 block 缓存，避免 compaction 淘汰缓存中的热点数据
+    pub fn entries(&self) -> Result<Vec<Key>> {
+        let mut file = self.file.lock().unwrap();
+        let mut result = Vec::new();
+        for entry in &self.index {
+            result.append(&mut decode_block(read_block(&mut file, entry.handle)?)?);
+        }
+        Ok(result)
+    }
+
+    /// 读取并解码 data block，优先从 block 缓存中读取
+    fn data_block(&self, handle: BlockHandle) -> Result<Arc<Vec<Key>>> {
+        let read = || read_block(&mut self.file.lock().unwrap(), handle);
+        let (number, cache) = match &self.block_cache {
+            Some(block_cache) => block_cache,
+            None => return Ok(Arc::new(decode_block(read()?)?)),
+        };
+        let cache_key = (*number, handle.offset);
+        if let Some(block) = cache.get(&cache_key) {
+            return Ok(block);
+        }
+        // 缓存的是解压、解码之后的 block，按照解压之后的大小计算容量
+        let content = read()?;
+        let charge = content.len();
+        let block = Arc::new(decode_block(content)?);
+        cache.insert(cache_key, block.clone(), charge);
+        Ok(block)
+    }
+}
+
+/// 读取并校验文件尾部的 footer
+fn read_footer(file: &mut File, path: &Path) -> Result<Footer> {
+    let file_len = file.metadata()?.len();
+    if file_len < FOOTER_SIZE as u64 {
+        return Err(anyhow::Error::from(WiscError::TableFormatInvalid(
+            path.to_string_lossy().to_string(),
+        )));
+    }
+    let mut footer_byte = vec![0_u8; FOOTER_SIZE];
+    file.seek(SeekFrom::Start(file_len - FOOTER_SIZE as u64))?;
+    file.read_exact(&mut footer_byte)?;
+    Footer::decode(&footer_byte, path)
+}
+
+/// 读取 block 并解压
+fn read_block(file: &mut File, handle: BlockHandle) -> Result<ByteVec> {
+    let (compression, content) = read_stored_block(file, handle)?;
+    decompress(compression, content)
+}
+
+/// 读取 block 在文件中保存的内容并校验尾部的 crc32，返回压缩方式和压缩之后的内容
+fn read_stored_block(file: &mut File, handle: BlockHandle) -> Result<(CompressionType, ByteVec)> {
+    let mut content = vec![0_u8; handle.size as usize + BLOCK_TRAILER_SIZE];
+    file.seek(SeekFrom::Start(handle.offset))?;
+    file.read_exact(&mut content)?;
+    let trailer = content.split_off(handle.size as usize + 1);
+    let saved_checksum = u32::from_le_bytes(trailer.as_slice().try_into()?);
+    let checksum = checksum(&content);
+    if checksum != saved_checksum {
+        return Err(anyhow::Error::from(WiscError::DataCorruption {
+            checksum,
+            saved_checksum,
+        }));
+    }
+    let compression = CompressionType::from_byte(content.pop().unwrap_or_default())?;
+    Ok((compression, content))
+}
+
+/// 数据文件的统计信息，用于查看压缩效果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableProperties {
+    pub file_size: u64,
+    pub entries: usize,
+    pub data_blocks: usize,
+    /// 所有 data block 压缩之前的大小
+    pub raw_size: u64,
+    /// 所有 data block 在文件中的大小
+    pub stored_size: u64,
+    /// 每种压缩方式的 data block 个数，压缩之后没有变小的 block 以原始内容保存
+    pub compressions: BTreeMap<CompressionType, usize>,
+}
+impl TableProperties {
+    /// 压缩比：压缩之前的大小 / 压缩之后的大小
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_size == 0 {
+            return 1.0;
+        }
+        self.raw_size as f64 / self.stored_size as f64
+    }
+}
+
+/// 读取数据文件中所有的 data block，统计压缩前后的大小
+pub fn table_properties(path: &Path) -> Result<TableProperties> {
+    let mut file = File::open(path)?;
+    let footer = read_footer(&mut file, path)?;
+    let index_byte = read_block(&mut file, footer.index_handle)?;
+    let mut properties = TableProperties {
+        file_size: file.metadata()?.len(),
+        ..TableProperties::default()
+    };
+    for entry in bincode::deserialize::<Vec<IndexEntry>>(&index_byte)? {
+        let (compression, content) = read_stored_block(&mut file, entry.handle)?;
+        let content = decompress(compression, content)?;
+        properties.data_blocks += 1;
+        properties.stored_size += entry.handle.size;
+        properties.raw_size += content.len() as u64;
+        properties.entries += decode_block(content)?.len();
+        *properties.compressions.entry(compression).or_insert(0) += 1;
+    }
+    Ok(properties)
+}
+
+/// 数据目录中每个层级的所有数据文件的统计信息：(层级, 文件编号, 统计信息)
+pub fn dump_tables(data_dir: &Path) -> Result<Vec<(u8, u64, TableProperties)>> {
+    let mut result = Vec::new();
+    for level in SERVER_CONFIG.level_dirs.iter() {
+        for (number, path) in LevelDir::new(data_dir, *level).table_files()? {
+            result.push((*level, number, table_properties(&path)?));
+        }
+    }
+    Ok(result)
+}
+
+/// 解码 data block
+///
+/// data block 由连续的 `Key::encode` 字节组成：internal_key_size(8) + internal_key + value_size(8) + value。
+/// 记录的长度超出 block 时返回 `WiscError::TableFormatInvalid`
+pub fn decode_block(mut content: ByteVec) -> Result<Vec<Key>> {
+    let invalid = || anyhow::Error::from(WiscError::TableFormatInvalid("data block".to_string()));
+    let mut keys = Vec::new();
+    while content.len() >= 8 {
+        let internal_key_size = bincode::deserialize::<u64>(&content[..8])? as usize;
+        let value_size_start = internal_key_size.checked_add(8).ok_or_else(invalid)?;
+        let value_size_byte = value_size_start
+            .checked_add(8)
+            .and_then(|end| content.get(value_size_start..end))
+            .ok_or_else(invalid)?;
+        let value_size = bincode::deserialize::<u64>(value_size_byte)? as usize;
+        let entry_len = (value_size_start + 8)
+            .checked_add(value_size)
+            .filter(|len| *len <= content.len())
+            .ok_or_else(invalid)?;
+        let rest = content.split_off(entry_len);
+        keys.push(Key::decode(&mut content)?);
+        content = rest;
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engines::lsm_log_engine::comparator::MAX_SEQUENCE;
+    use crate::engines::lsm_log_engine::wal_log::DataType;
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::ops::Bound;
+
+    #[test]
+    fn build_and_read_test() -> Result<()> {
+        let dir = env::temp_dir().join("r_wisckey_sstable_test");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir)?;
+        let path = dir.join("1.wisc");
+
+        let mut keys: Vec<Key> = (0..2000)
+            .map(|i| {
+                Key::new(
+                    format!("key_{:05}", i),
+                    format!("value_{}", i),
+                    DataType::Set,
+                )
+            })
+            .collect();
+        let old_sequence = keys[1].sequence();
+        keys.push(Key::new(
+            "key_00001".to_string(),
+            "new".to_string(),
+            DataType::Set,
+        ));
+        let comparator = Arc::new(InternalKeyComparator::default());
+        keys.sort_by(|a, b| comparator.compare(&a.internal_key(), &b.internal_key()));
+
+        let mut builder = TableBuilder::new(path.clone(), CompressionType::Zstd)?;
+        for key in keys.iter() {
+            builder.add(key)?;
+        }
+        let info = builder.finish()?;
+        assert_eq!(info.smallest, b"key_00000");
+        assert_eq!(info.largest, b"key_01999");
+
+        let properties = table_properties(&path)?;
+        assert_eq!(properties.entries, 2001);
+        assert_eq!(properties.file_size, info.file_size);
+        assert!(properties.compression_ratio() > 1.0);
+
+        let reader = TableReader::open(path, comparator)?;
+        assert!(reader.index.len() > 1);
+        assert_eq!(
+            reader.get(b"key_00001", MAX_SEQUENCE)?.unwrap().value(),
+            b"new"
+        );
+        assert_eq!(
+            reader.get(b"key_00001", old_sequence)?.unwrap().value(),
+            b"value_1"
+        );
+        assert_eq!(
+            reader.get(b"key_01999", MAX_SEQUENCE)?.unwrap().value(),
+            b"value_1999"
+        );
+        assert!(reader.get(b"key_02000", MAX_SEQUENCE)?.is_none());
+        assert!(reader.get(b"a", MAX_SEQUENCE)?.is_none());
+
+        let range = Scans::new(
+            Bound::Included(b"key_00001".to_vec()),
+            Bound::Excluded(b"key_00003".to_vec()),
+            None,
+        );
+        // key_00001 的两个版本 + key_00002
+        assert_eq!(reader.scan(&range, MAX_SEQUENCE)?.len(), 3);
+        // key_00002 在 key_00001 的旧版本之后写入，同样不可见
+        assert_eq!(reader.scan(&range, old_sequence)?.len(), 1);
+        assert_eq!(reader.entries()?.len(), 2001);
+
+        assert!(reader.may_contain(b"key_00001"));
+        assert!(reader.may_contain(b"key_01999"));
+
+        // 第二次读取同一个 block 时命中缓存
+        let block_cache = Arc::new(BlockCache::new(1024 * 1024));
+        let reader = reader.with_block_cache(1, block_cache.clone());
+        reader.get(b"key_00001", MAX_SEQUENCE)?;
+        reader.get(b"key_00002", MAX_SEQUENCE)?;
+        let statistics = block_cache.statistics();
+        assert_eq!((statistics.hits, statistics.misses), (1, 1));
+        assert!(statistics.usage > 0);
+
+        remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn decode_corrupted_block_test() -> Result<()> {
+        let block = Key::with_sequence("key", "value", DataType::Set, 1).encode();
+        assert_eq!(decode_block(block.clone())?.len(), 1);
+
+        let mut huge_key_size = block.clone();
+        huge_key_size[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut huge_value_size = block.clone();
+        let value_size_start = block.len() - b"value".len() - 8;
+        huge_value_size[value_size_start..value_size_start + 8]
+            .copy_from_slice(&u64::MAX.to_le_bytes());
+        for corrupted in [
+            block[..block.len() - 1].to_vec(),
+            block[..12].to_vec(),
+            huge_key_size,
+            huge_value_size,
+        ] {
+            assert!(matches!(
+                decode_block(corrupted)
+                    .unwrap_err()
+                    .downcast_ref::<WiscError>(),
+                Some(WiscError::TableFormatInvalid(_))
+            ));
+        }
+        Ok(())
+    }
+}
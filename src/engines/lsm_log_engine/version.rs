@@ -0,0 +1,678 @@
+//! MANIFEST 和 CURRENT，记录每个层级中存活的 SSTable
+//!
+//! MANIFEST 是 `VersionEdit` 的追加日志，每条 edit 记录新增、删除的数据文件以及 WAL 编号和 sequence，
+//! 从头重放所有的 edit 即可得到当前的 `Version`。CURRENT 中只保存当前生效的 MANIFEST 文件名，
+//! 通过写临时文件再重命名的方式原子地切换。
+//!
+//! 单条 edit 记录的布局：
+//!
+//! ```text
+//! | checksum(4) | len(4) | bincode(VersionEdit) |
+//! ```
+
+#![allow(dead_code)]
+
+use anyhow::Result;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fs::{read, read_dir, read_to_string, remove_file, rename, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+
+use crate::common::error_enum::WiscError;
+use crate::common::fn_util::{
+    checksum, gen_file_number, next_file_number, restore_file_number, restore_sequence, sync_dir,
+};
+use crate::common::types::ByteVec;
+use crate::config::SERVER_CONFIG;
+use crate::engines::lsm_log_engine::cache::TableCache;
+use crate::engines::lsm_log_engine::compaction::Compaction;
+use crate::engines::lsm_log_engine::comparator::{Comparator, InternalKeyComparator, MAX_SEQUENCE};
+use crate::engines::lsm_log_engine::level::{level_max_bytes, LevelDir, LEVEL_0_FILE_MAX_NUM};
+use crate::engines::lsm_log_engine::sstable::{TableInfo, TableReader};
+use crate::engines::lsm_log_engine::wal_log::Key;
+use crate::engines::Scans;
+
+/// CURRENT 文件名
+pub const CURRENT_FILE: &str = "CURRENT";
+/// MANIFEST 文件名前缀，完整的文件名为 `MANIFEST-<number>`
+pub const MANIFEST_PREFIX: &str = "MANIFEST-";
+/// checksum(4) + len(4)
+pub const EDIT_HEADER_SIZE: usize = 4 + 4;
+
+/// 单个 SSTable 的元数据
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileMetaData {
+    pub level: u8,
+    /// 文件编号，也就是文件名
+    pub number: u64,
+    pub file_size: u64,
+    /// 最小的用户 key
+    pub smallest: ByteVec,
+    /// 最大的用户 key
+    pub largest: ByteVec,
+    pub smallest_seq: i64,
+    pub largest_seq: i64,
+}
+impl FileMetaData {
+    pub fn new(level: u8, number: u64, info: TableInfo) -> Self {
+        FileMetaData {
+            level,
+            number,
+            file_size: info.file_size,
+            smallest: info.smallest,
+            largest: info.largest,
+            smallest_seq: info.smallest_seq,
+            largest_seq: info.largest_seq,
+        }
+    }
+
+    /// 用户 key 是否落在该文件的 key 范围内
+    pub fn contains(&self, comparator: &InternalKeyComparator, key: &[u8]) -> bool {
+        comparator.compare_user_key(&self.smallest, key).is_le()
+            && comparator.compare_user_key(key, &self.largest).is_le()
+    }
+
+    /// 文件的 key 范围是否与查询范围相交
+    pub fn overlaps(&self, comparator: &InternalKeyComparator, range: &Scans) -> bool {
+        let user_comparator = comparator.user_comparator();
+        range.after_start(user_comparator, &self.largest)
+            && range.before_end(user_comparator, &self.smallest)
+    }
+
+    pub fn path(&self, data_dir: &Path) -> Result<PathBuf> {
+        LevelDir::new(data_dir, self.level).file_path(self.number)
+    }
+}
+
+/// 一次对文件集合的修改
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VersionEdit {
+    /// 新增的文件
+    pub added: Vec<FileMetaData>,
+    /// 删除的文件：(level, number)
+    pub removed: Vec<(u8, u64)>,
+    /// 编号小于它的 WAL 文件中的数据都已经持久化到 SSTable 中
+    pub log_number: Option<u64>,
+    /// 已经持久化的最大 sequence
+    pub last_sequence: Option<i64>,
+    /// 下一个将要分配的文件编号
+    pub next_file_number: Option<u64>,
+    /// 用户 key 比较器的名称，只记录在 MANIFEST 的第一条 edit 中
+    pub comparator: Option<String>,
+}
+impl VersionEdit {
+    pub fn encode(&self) -> Result<ByteVec> {
+        let body = bincode::serialize(self)?;
+        let mut buf = ByteVec::with_capacity(EDIT_HEADER_SIZE + body.len());
+        buf.extend_from_slice(&checksum(&body).to_le_bytes());
+        buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&body);
+        Ok(buf)
+    }
+
+    /// 解码 MANIFEST 中所有的 edit
+    ///
+    /// 尾部不完整的记录说明写入时发生了崩溃，该 edit 没有提交，直接忽略
+    pub fn decode_all(content: &[u8]) -> Result<Vec<VersionEdit>> {
+        let mut edits = Vec::new();
+        let mut offset = 0;
+        while offset + EDIT_HEADER_SIZE <= content.len() {
+            let saved_checksum = u32::from_le_bytes(content[offset..offset + 4].try_into()?);
+            let len = u32::from_le_bytes(content[offset + 4..offset + 8].try_into()?) as usize;
+            let body_start = offset + EDIT_HEADER_SIZE;
+            if body_start + len > content.len() {
+                warn!(
+                    "MANIFEST 尾部记录不完整，忽略 {} 字节",
+                    content.len() - offset
+                );
+                break;
+            }
+            let body = &content[body_start..body_start + len];
+            let checksum = checksum(body);
+            if checksum != saved_checksum {
+                return Err(anyhow::Error::from(WiscError::DataCorruption {
+                    checksum,
+                    saved_checksum,
+                }));
+            }
+            edits.push(bincode::deserialize(body)?);
+            offset = body_start + len;
+        }
+        Ok(edits)
+    }
+}
+
+/// 某一时刻所有层级中存活的文件集合，不可变
+///
+/// level-0 的文件按照编号从新到旧排列，其他层级的文件之间 key 范围不重叠，按照最小 key 排列
+#[derive(Debug, Clone)]
+pub struct Version {
+    levels: Vec<Vec<FileMetaData>>,
+    comparator: Arc<InternalKeyComparator>,
+    /// 所有 version 共享的已打开文件缓存
+    tables: Arc<TableCache>,
+}
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.levels == other.levels
+    }
+}
+impl Version {
+    /// 不包含任何文件的 version
+    pub fn new(comparator: Arc<InternalKeyComparator>) -> Self {
+        Version {
+            levels: vec![Vec::new(); SERVER_CONFIG.level_dirs.len()],
+            comparator,
+            tables: Arc::default(),
+        }
+    }
+
+    pub fn comparator(&self) -> Arc<InternalKeyComparator> {
+        self.comparator.clone()
+    }
+
+    pub fn table_cache(&self) -> &TableCache {
+        &self.tables
+    }
+
+    /// 在当前 version 的基础上应用 edit，得到新的 version
+    pub fn apply(&self, edit: &VersionEdit) -> Version {
+        let mut levels = self.levels.clone();
+        for (level, number) in edit.removed.iter() {
+            levels[*level as usize].retain(|file| file.number != *number);
+        }
+        for file in edit.added.iter() {
+            levels[file.level as usize].push(file.clone());
+        }
+        for (level, files) in levels.iter_mut().enumerate() {
+            if level == 0 {
+                files.sort_by_key(|file| Reverse(file.number));
+            } else {
+                files.sort_by(|a, b| self.comparator.compare_user_key(&a.smallest, &b.smallest));
+            }
+        }
+        Version {
+            levels,
+            comparator: self.comparator.clone(),
+            tables: self.tables.clone(),
+        }
+    }
+
+    /// 指定层级中的文件
+    pub fn files(&self, level: u8) -> &[FileMetaData] {
+        &self.levels[level as usize]
+    }
+
+    /// 所有层级中的文件
+    pub fn all_files(&self) -> impl Iterator<Item = &FileMetaData> {
+        self.levels.iter().flatten()
+    }
+
+    /// 生成一个包含当前所有文件的 edit，用于写入新的 MANIFEST
+    pub fn snapshot_edit(&self) -> VersionEdit {
+        VersionEdit {
+            added: self.all_files().cloned().collect(),
+            ..VersionEdit::default()
+        }
+    }
+
+    /// 指定层级中所有文件的总大小
+    pub fn level_size(&self, level: u8) -> u64 {
+        self.files(level).iter().map(|file| file.file_size).sum()
+    }
+
+    /// 层级的压缩得分，不小于 1 时需要向下一层压缩
+    ///
+    /// level-0 按照文件数量计算，其他层级按照总大小计算，最后一层没有可以压缩的下一层
+    pub fn compaction_score(&self, level: u8) -> f64 {
+        if level as usize + 1 >= self.levels.len() {
+            return 0.0;
+        }
+        if level == 0 {
+            return self.files(0).len() as f64 / LEVEL_0_FILE_MAX_NUM as f64;
+        }
+        self.level_size(level) as f64 / level_max_bytes(level) as f64
+    }
+
+    /// 指定层级中 key 范围与 [smallest, largest] 相交的文件
+    pub fn overlapping_files(
+        &self,
+        level: u8,
+        smallest: &[u8],
+        largest: &[u8],
+    ) -> Vec<FileMetaData> {
+        self.files(level)
+            .iter()
+            .filter(|file| {
+                self.comparator
+                    .compare_user_key(&file.smallest, largest)
+                    .is_le()
+                    && self
+                        .comparator
+                        .compare_user_key(smallest, &file.largest)
+                        .is_le()
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// 指定层级中 key 范围包含 key 的文件
+    ///
+    /// level-0 可能有多个（从新到旧），其他层级最多一个
+    pub fn files_for_key(&self, level: u8, key: &[u8]) -> Vec<&FileMetaData> {
+        let files = self.files(level);
+        if level == 0 {
+            return files
+                .iter()
+                .filter(|file| file.contains(&self.comparator, key))
+                .collect();
+        }
+        let index = files
+            .partition_point(|file| self.comparator.compare_user_key(&file.largest, key).is_lt());
+        files
+            .get(index)
+            .filter(|file| file.contains(&self.comparator, key))
+            .into_iter()
+            .collect()
+    }
+
+    /// 逐层查找 key 中 sequence 不大于 `sequence` 的最新版本，先找到的即最新版本
+    pub fn get(&self, data_dir: &Path, key: &[u8], sequence: i64) -> Result<Option<Key>> {
+        for level in SERVER_CONFIG.level_dirs.iter() {
+            for file in self.files_for_key(*level, key) {
+                if let Some(internal_key) = self.table_get(data_dir, file, key, sequence)? {
+                    return Ok(Some(internal_key));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// 在单个文件中查找 key，先检查过滤器，过滤器确定不存在时不读取 data block
+    fn table_get(
+        &self,
+        data_dir: &Path,
+        file: &FileMetaData,
+        key: &[u8],
+        sequence: i64,
+    ) -> Result<Option<Key>> {
+        let table = self.table(data_dir, file)?;
+        if !self.tables.may_contain(&table, key) {
+            return Ok(None);
+        }
+        let found = table.get(key, sequence)?;
+        if found.is_none() && sequence == MAX_SEQUENCE {
+            self.tables.record_false_positive();
+        }
+        Ok(found)
+    }
+
+    /// 范围查询，每个与范围相交的文件返回一组 sequence 不大于 `sequence`、
+    /// 按（用户 key 升序，sequence 降序）排列的结果
+    pub fn scan(&self, data_dir: &Path, range: &Scans, sequence: i64) -> Result<Vec<Vec<Key>>> {
+        let mut result = Vec::new();
+        for file in self
+            .all_files()
+            .filter(|file| file.overlaps(&self.comparator, range))
+        {
+            result.push(self.table(data_dir, file)?.scan(range, sequence)?);
+        }
+        Ok(result)
+    }
+
+    /// 比 level 更深的层级中是否存在 key 的任何版本
+    pub fn key_exists_below(&self, data_dir: &Path, key: &[u8], level: u8) -> Result<bool> {
+        for deeper in SERVER_CONFIG.level_dirs.iter().filter(|ele| **ele > level) {
+            for file in self.files_for_key(*deeper, key) {
+                if self.table_get(data_dir, file, key, MAX_SEQUENCE)?.is_some() {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// 打开数据文件，使用当前 version 的比较器
+    ///
+    /// 不经过文件缓存和 block 缓存，用于 compaction 等一次性的顺序读取
+    pub fn open_table(&self, data_dir: &Path, file: &FileMetaData) -> Result<TableReader> {
+        TableReader::open(file.path(data_dir)?, self.comparator.clone())
+    }
+
+    /// 从文件缓存中获取数据文件，用于用户的读取
+    fn table(&self, data_dir: &Path, file: &FileMetaData) -> Result<Arc<TableReader>> {
+        self.tables
+            .table(file.number, file.path(data_dir)?, self.comparator.clone())
+    }
+}
+
+/// 管理当前 version 以及 MANIFEST 的写入
+#[derive(Debug)]
+pub struct VersionSet {
+    data_dir: PathBuf,
+    current: Arc<Version>,
+    manifest_number: u64,
+    manifest_writer: BufWriter<File>,
+    /// 编号小于它的 WAL 文件都已经持久化
+    log_number: u64,
+    /// 已经持久化到 SSTable 中的最大 sequence
+    last_sequence: i64,
+    /// 被替换掉、但可能仍然被读取使用的旧 version
+    old_versions: Vec<Weak<Version>>,
+    /// 已经从 version 中删除、等待没有 version 引用之后才能删除的数据文件
+    obsolete_files: Vec<FileMetaData>,
+    /// 每个层级下次压缩开始的 key，使压缩轮流覆盖整个层级
+    compact_pointers: Vec<ByteVec>,
+    /// 是否有 major compaction 正在进行
+    compacting: bool,
+}
+impl VersionSet {
+    /// 根据 CURRENT 指向的 MANIFEST 恢复文件集合
+    ///
+    /// 恢复之后总是写入一个新的 MANIFEST（只包含一条快照 edit），切换 CURRENT，
+    /// 并删除旧的 MANIFEST 以及不属于当前 version 的数据文件
+    pub fn open(data_dir: &Path, comparator: Arc<InternalKeyComparator>) -> Result<Self> {
+        let mut version = Version::new(comparator.clone());
+        let mut log_number = 0;
+        let mut last_sequence = 0;
+        let mut file_number = 0;
+        let current_path = data_dir.join(CURRENT_FILE);
+        if current_path.exists() {
+            let manifest_name = read_to_string(&current_path)?;
+            let manifest_path = data_dir.join(manifest_name.trim());
+            if !manifest_path.exists() {
+                return Err(anyhow::Error::from(WiscError::FileNotFound(
+                    manifest_path.to_string_lossy().to_string(),
+                )));
+            }
+            for edit in VersionEdit::decode_all(&read(&manifest_path)?)? {
+                version = version.apply(&edit);
+                log_number = edit.log_number.unwrap_or(log_number);
+                last_sequence = edit.last_sequence.unwrap_or(last_sequence);
+                file_number = edit.next_file_number.unwrap_or(file_number);
+                match edit.comparator {
+                    Some(name) if name != comparator.name() => {
+                        return Err(anyhow::Error::from(WiscError::ComparatorMismatch(
+                            name,
+                            comparator.name().to_string(),
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+            info!("从 {:?} 恢复文件集合", manifest_path);
+        }
+
+        // 之后生成的 sequence 和文件编号都不能与已经持久化的重复
+        restore_sequence(last_sequence);
+        let max_table_number = version.all_files().map(|file| file.number).max();
+        restore_file_number(file_number.max(max_table_number.map_or(0, |number| number + 1)));
+        let manifest_number = gen_file_number();
+        let manifest_writer = create_manifest(data_dir, manifest_number)?;
+        let mut version_set = VersionSet {
+            data_dir: data_dir.to_path_buf(),
+            current: Arc::new(version),
+            manifest_number,
+            manifest_writer,
+            log_number,
+            last_sequence,
+            old_versions: Vec::new(),
+            obsolete_files: Vec::new(),
+            compact_pointers: vec![ByteVec::new(); SERVER_CONFIG.level_dirs.len()],
+            compacting: false,
+        };
+        let mut snapshot = version_set.current.snapshot_edit();
+        snapshot.log_number = Some(log_number);
+        snapshot.last_sequence = Some(last_sequence);
+        snapshot.next_file_number = Some(next_file_number());
+        snapshot.comparator = Some(version_set.current.comparator.name().to_string());
+        version_set.write_edit(&snapshot)?;
+        set_current(data_dir, manifest_number)?;
+        version_set.remove_obsolete_files()?;
+        Ok(version_set)
+    }
+
+    /// 当前的 version
+    pub fn current(&self) -> Arc<Version> {
+        self.current.clone()
+    }
+
+    pub fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    pub fn last_sequence(&self) -> i64 {
+        self.last_sequence
+    }
+
+    /// 将 edit 持久化到 MANIFEST 之后再应用到当前 version
+    ///
+    /// 被删除的数据文件不会立即删除，直到没有任何 version 再引用它们
+    /// 每条 edit 都会记录下一个文件编号，edit 中的 last_sequence 只会使其增大
+    pub fn log_and_apply(&mut self, mut edit: VersionEdit) -> Result<()> {
+        edit.next_file_number = Some(next_file_number());
+        self.write_edit(&edit)?;
+        for (level, number) in edit.removed.iter() {
+            if let Some(file) = self
+                .current
+                .files(*level)
+                .iter()
+                .find(|file| file.number == *number)
+            {
+                self.obsolete_files.push(file.clone());
+            }
+        }
+        let version = Arc::new(self.current.apply(&edit));
+        let old = std::mem::replace(&mut self.current, version);
+        self.old_versions.push(Arc::downgrade(&old));
+        drop(old);
+        if let Some(log_number) = edit.log_number {
+            self.log_number = log_number;
+        }
+        if let Some(last_sequence) = edit.last_sequence {
+            self.last_sequence = self.last_sequence.max(last_sequence);
+        }
+        self.purge_obsolete_files()
+    }
+
+    /// 选择下一次 major compaction，没有需要压缩的层级时返回 `None`
+    ///
+    /// 同一时刻只允许一个 major compaction，返回 `Some` 之后必须调用 `finish_compaction`；
+    /// 选择和结束标记在同一次加锁中完成，保证压缩期间新 flush 的文件不会被遗漏
+    pub fn pick_compaction(&mut self) -> Option<Compaction> {
+        if self.compacting {
+            return None;
+        }
+        let compaction = Compaction::pick(&self.current, &self.compact_pointers);
+        self.compacting = compaction.is_some();
+        compaction
+    }
+
+    /// 提交一次 major compaction 的结果，并记录该层级下次压缩开始的位置
+    pub fn finish_compaction(&mut self, compaction: &Compaction, edit: VersionEdit) -> Result<()> {
+        self.compacting = false;
+        self.compact_pointers[compaction.level as usize] =
+            compaction.largest(&self.current.comparator);
+        self.log_and_apply(edit)
+    }
+
+    /// 放弃一次失败的 major compaction
+    pub fn abort_compaction(&mut self) {
+        self.compacting = false;
+    }
+
+    /// 删除不再被任何 version 引用的数据文件
+    fn purge_obsolete_files(&mut self) -> Result<()> {
+        self.old_versions
+            .retain(|version| version.strong_count() > 0);
+        let live: Vec<Arc<Version>> = self
+            .old_versions
+            .iter()
+            .filter_map(|version| version.upgrade())
+            .chain(std::iter::once(self.current.clone()))
+            .collect();
+        let (in_use, removable): (Vec<FileMetaData>, Vec<FileMetaData>) =
+            std::mem::take(&mut self.obsolete_files)
+                .into_iter()
+                .partition(|file| {
+                    live.iter()
+                        .any(|version| version.files(file.level).contains(file))
+                });
+        self.obsolete_files = in_use;
+        for file in removable {
+            info!("删除已经压缩的数据文件 {:?}", file.path(&self.data_dir)?);
+            remove_file(file.path(&self.data_dir)?)?;
+            self.current.tables.evict(file.number);
+        }
+        Ok(())
+    }
+
+    /// 追加一条 edit 并 fsync
+    fn write_edit(&mut self, edit: &VersionEdit) -> Result<()> {
+        self.manifest_writer.write_all(&edit.encode()?)?;
+        self.manifest_writer.flush()?;
+        self.manifest_writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// 删除旧的 MANIFEST、写入中断残留的临时文件以及不属于当前 version 的数据文件
+    fn remove_obsolete_files(&self) -> Result<()> {
+        let current_manifest = manifest_file_name(self.manifest_number);
+        for entry in read_dir(&self.data_dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            if name.starts_with(MANIFEST_PREFIX) && name != current_manifest {
+                remove_file(&path)?;
+            }
+        }
+        for level in SERVER_CONFIG.level_dirs.iter() {
+            let live: Vec<u64> = self
+                .current
+                .files(*level)
+                .iter()
+                .map(|f| f.number)
+                .collect();
+            for entry in read_dir(LevelDir::new(&self.data_dir, *level).to_path()?)? {
+                let path = entry?.path();
+                let number = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok());
+                let is_table = path.extension().and_then(|ext| ext.to_str())
+                    == Some(SERVER_CONFIG.data_file_extension.as_str());
+                match number {
+                    Some(number) if is_table && live.contains(&number) => {}
+                    _ => {
+                        info!("删除不属于当前 version 的文件 {:?}", path);
+                        remove_file(&path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn manifest_file_name(manifest_number: u64) -> String {
+    format!("{}{}", MANIFEST_PREFIX, manifest_number)
+}
+
+/// 创建新的 MANIFEST 文件
+fn create_manifest(data_dir: &Path, manifest_number: u64) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(data_dir.join(manifest_file_name(manifest_number)))?;
+    Ok(BufWriter::new(file))
+}
+
+/// 原子地将 CURRENT 指向新的 MANIFEST：先写临时文件，fsync 之后重命名
+fn set_current(data_dir: &Path, manifest_number: u64) -> Result<()> {
+    let temp_path = data_dir.join(format!("{}.tmp", CURRENT_FILE));
+    let mut file = File::create(&temp_path)?;
+    writeln!(file, "{}", manifest_file_name(manifest_number))?;
+    file.sync_all()?;
+    rename(&temp_path, data_dir.join(CURRENT_FILE))?;
+    sync_dir(data_dir)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::fn_util::gen_sequence;
+    use crate::engines::lsm_log_engine::compression::CompressionType;
+    use crate::engines::lsm_log_engine::sstable::TableBuilder;
+    use crate::engines::lsm_log_engine::wal_log::DataType;
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    fn build_table(data_dir: &Path, level: u8, keys: &[&str]) -> Result<FileMetaData> {
+        let number = gen_file_number();
+        let mut builder = TableBuilder::new(
+            LevelDir::new(data_dir, level).file_path(number)?,
+            CompressionType::for_level(level),
+        )?;
+        for key in keys {
+            builder.add(&Key::new(*key, *key, DataType::Set))?;
+        }
+        Ok(FileMetaData::new(level, number, builder.finish()?))
+    }
+
+    #[test]
+    fn recover_test() -> Result<()> {
+        let data_dir = env::temp_dir().join("r_wisckey_version_test");
+        let _ = remove_dir_all(&data_dir);
+        create_dir_all(&data_dir)?;
+
+        let mut version_set = VersionSet::open(&data_dir, Default::default())?;
+        let file_a = build_table(&data_dir, 0, &["a", "b"])?;
+        let file_b = build_table(&data_dir, 0, &["b", "c"])?;
+        let file_c = build_table(&data_dir, 1, &["d"])?;
+        version_set.log_and_apply(VersionEdit {
+            added: vec![file_a.clone(), file_b.clone()],
+            log_number: Some(10),
+            last_sequence: Some(1 << 40),
+            ..VersionEdit::default()
+        })?;
+        version_set.log_and_apply(VersionEdit {
+            added: vec![file_c.clone()],
+            removed: vec![(0, file_a.number)],
+            ..VersionEdit::default()
+        })?;
+        // 没有提交到 MANIFEST 的文件
+        let orphan = build_table(&data_dir, 1, &["e"])?;
+        let expected = version_set.current();
+        assert_eq!(expected.files(0), std::slice::from_ref(&file_b));
+        assert_eq!(
+            expected.get(&data_dir, b"b", MAX_SEQUENCE)?.unwrap().key(),
+            b"b"
+        );
+        assert_eq!(
+            expected.get(&data_dir, b"d", MAX_SEQUENCE)?.unwrap().key(),
+            b"d"
+        );
+        assert!(expected.get(&data_dir, b"a", MAX_SEQUENCE)?.is_none());
+        drop(version_set);
+
+        let version_set = VersionSet::open(&data_dir, Default::default())?;
+        assert_eq!(*version_set.current(), *expected);
+        assert_eq!(version_set.log_number(), 10);
+        assert_eq!(version_set.last_sequence(), 1 << 40);
+        // 重新打开之后 sequence 和文件编号都不会重复使用
+        assert!(gen_sequence() > 1 << 40);
+        assert!(gen_file_number() > file_c.number);
+        assert!(!orphan.path(&data_dir)?.exists());
+        assert!(!file_a.path(&data_dir)?.exists());
+        assert!(file_c.path(&data_dir)?.exists());
+
+        remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+//! value log（vLog），WiscKey 的 key-value 分离
+//!
+//! 超过阈值的 value 追加写入 vLog，LSM 中只保存指向它的 `ValuePointer`，
+//! 这样压缩时只需要移动很小的 key，显著降低写放大。
+//!
+//! 单条 entry 的布局：
+//!
+//! ```text
+//! | checksum(4) | key_len(8) | value_len(8) | compression(1) | key | value |
+//! ```
+//!
+//! checksum 覆盖 key_len 之后的所有字节。value 按照 `vlog_compression` 压缩之后保存，
+//! value_len 为压缩之后的长度，compression 记录实际使用的压缩方式
+
+#![allow(dead_code)]
+
+use anyhow::Result;
+use std::fs::{create_dir_all, read, remove_file, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::common::error_enum::WiscError;
+use crate::common::fn_util::{
+    checksum, gen_file_number, get_file_path, open_option_default, restore_file_number,
+    sorted_gen_list, sync_dir,
+};
+use crate::common::types::ByteVec;
+use crate::config::SERVER_CONFIG;
+use crate::engines::lsm_log_engine::compression::{compress, decompress, CompressionType};
+
+/// 单个 vLog 文件的最大大小 16M，超过之后切换新的文件
+pub const VLOG_FILE_MAX_SIZE: u64 = 1024 * 1024 * 16;
+/// checksum(4) + key_len(8) + value_len(8) + compression(1)
+pub const VLOG_ENTRY_HEADER_SIZE: usize = 4 + 8 + 8 + 1;
+
+/// LSM 中保存的指向 vLog entry 的指针
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValuePointer {
+    /// vLog 文件编号
+    pub file_id: u64,
+    /// entry 在文件中的起始位置
+    pub offset: u64,
+    /// entry 的总长度（包含 header 和 key）
+    pub len: u64,
+}
+impl ValuePointer {
+    /// 编码为 `Key` 中的 value，格式：`file_id:offset:len`
+    pub fn encode(&self) -> ByteVec {
+        format!("{}:{}:{}", self.file_id, self.offset, self.len).into_bytes()
+    }
+
+    pub fn decode(content: &[u8]) -> Result<Self> {
+        let invalid = || {
+            anyhow::Error::from(WiscError::ValuePointerInvalid(
+                String::from_utf8_lossy(content).to_string(),
+            ))
+        };
+        let parts: Vec<&str> = std::str::from_utf8(content)
+            .map_err(|_| invalid())?
+            .split(':')
+            .collect();
+        if parts.len() != 3 {
+            return Err(invalid());
+        }
+        Ok(ValuePointer {
+            file_id: parts[0].parse()?,
+            offset: parts[1].parse()?,
+            len: parts[2].parse()?,
+        })
+    }
+}
+
+/// vLog 中的一条 entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct VlogEntry {
+    pub key: ByteVec,
+    pub value: ByteVec,
+}
+impl VlogEntry {
+    /// 编码为一条 entry，value 使用 `compression` 压缩
+    pub fn encode(&self, compression: CompressionType) -> Result<ByteVec> {
+        let (compression, value) = compress(compression, &self.value)?;
+        let mut body = ByteVec::new();
+        body.extend_from_slice(&(self.key.len() as u64).to_le_bytes());
+        body.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        body.push(compression as u8);
+        body.extend_from_slice(&self.key);
+        body.extend_from_slice(&value);
+
+        let mut buf = ByteVec::with_capacity(body.len() + 4);
+        buf.extend_from_slice(&checksum(&body).to_le_bytes());
+        buf.append(&mut body);
+        Ok(buf)
+    }
+
+    /// 解码一条完整的 entry 并校验 checksum，value 解压之后返回
+    pub fn decode(content: &[u8]) -> Result<Self> {
+        if content.len() < VLOG_ENTRY_HEADER_SIZE {
+            return Err(anyhow::Error::from(WiscError::ValuePointerInvalid(
+                format!("entry len {}", content.len()),
+            )));
+        }
+        let saved_checksum = u32::from_le_bytes(content[0..4].try_into()?);
+        let checksum = checksum(&content[4..]);
+        if checksum != saved_checksum {
+            return Err(anyhow::Error::from(WiscError::DataCorruption {
+                checksum,
+                saved_checksum,
+            }));
+        }
+        let key_len = u64::from_le_bytes(content[4..12].try_into()?) as usize;
+        let value_len = u64::from_le_bytes(content[12..20].try_into()?) as usize;
+        let compression = CompressionType::from_byte(content[20])?;
+        let key_end = VLOG_ENTRY_HEADER_SIZE + key_len;
+        Ok(VlogEntry {
+            key: content[VLOG_ENTRY_HEADER_SIZE..key_end].to_vec(),
+            value: decompress(compression, content[key_end..key_end + value_len].to_vec())?,
+        })
+    }
+}
+
+/// 一次 vLog 垃圾回收的结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct VlogGcReport {
+    /// 被回收的 vLog 文件编号
+    pub file_id: u64,
+    /// 文件的总大小
+    pub total_bytes: u64,
+    /// 仍然存活、被重新追加到头部的字节数
+    pub live_bytes: u64,
+    /// 回收的字节数
+    pub reclaimed_bytes: u64,
+}
+
+/// vLog 写入和读取
+#[derive(Debug)]
+pub struct ValueLog {
+    dir: PathBuf,
+    /// 当前写入的文件编号
+    head_file_id: u64,
+    head_writer: BufWriter<File>,
+    /// 当前写入文件的另一个句柄，用于后台 fsync
+    sync_file: Arc<Mutex<File>>,
+    /// 当前写入文件的长度，也就是下一条 entry 的 offset
+    head_offset: u64,
+    /// 上次检查之后是否切换过文件，用于触发垃圾回收的检查
+    rotated: bool,
+}
+impl ValueLog {
+    /// 打开 vLog 目录
+    ///
+    /// 每次打开都从一个新的文件开始写入，旧文件只读，
+    /// 这样不会在上次异常退出时可能残缺的文件尾部之后继续追加
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let dir = vlog_dir(data_dir)?;
+        // 新文件的编号必须大于所有已经存在的 vLog 文件
+        let file_ids = sorted_gen_list(
+            &dir,
+            SERVER_CONFIG.vlog_file_extension.as_str(),
+            SERVER_CONFIG.vlog_file_suffix.as_str(),
+        )?;
+        if let Some(last) = file_ids.last() {
+            restore_file_number(last + 1);
+        }
+        let head_file_id = gen_file_number();
+        let file = open_option_default(vlog_file_path(&dir, head_file_id))?;
+        sync_dir(&dir)?;
+        Ok(ValueLog {
+            dir,
+            head_file_id,
+            sync_file: Arc::new(Mutex::new(file.try_clone()?)),
+            head_writer: BufWriter::new(file),
+            head_offset: 0,
+            rotated: false,
+        })
+    }
+
+    /// 目录中所有的 vLog 文件编号，从旧到新排列
+    pub fn file_ids(&self) -> Result<Vec<u64>> {
+        sorted_gen_list(
+            &self.dir,
+            SERVER_CONFIG.vlog_file_extension.as_str(),
+            SERVER_CONFIG.vlog_file_suffix.as_str(),
+        )
+    }
+
+    /// 除当前写入文件之外的所有 vLog 文件编号，从旧到新排列
+    pub fn sealed_file_ids(&self) -> Result<Vec<u64>> {
+        let mut file_ids = self.file_ids()?;
+        file_ids.retain(|file_id| *file_id != self.head_file_id);
+        Ok(file_ids)
+    }
+
+    /// 除当前写入文件之外的所有 vLog 文件的总大小
+    pub fn sealed_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for file_id in self.sealed_file_ids()? {
+            size += vlog_file_path(&self.dir, file_id).metadata()?.len();
+        }
+        Ok(size)
+    }
+
+    /// vLog 文件的大小
+    pub fn file_size(&self, file_id: u64) -> Result<u64> {
+        Ok(vlog_file_path(&self.dir, file_id).metadata()?.len())
+    }
+
+    /// 获取并重置切换文件的标记
+    pub fn take_rotated(&mut self) -> bool {
+        std::mem::take(&mut self.rotated)
+    }
+
+    /// value 是否需要分离到 vLog 中
+    pub fn should_separate(value: &[u8]) -> bool {
+        value.len() >= SERVER_CONFIG.value_threshold
+    }
+
+    /// 追加一条 entry，返回指向它的指针
+    ///
+    /// 返回之前会 flush 到操作系统，保证之后写入 WAL 的指针是可读的
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<ValuePointer> {
+        if self.head_offset >= VLOG_FILE_MAX_SIZE {
+            self.rotate()?;
+        }
+        let entry = VlogEntry {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+        .encode(SERVER_CONFIG.vlog_compression)?;
+        self.head_writer.write_all(&entry)?;
+        self.head_writer.flush()?;
+
+        let pointer = ValuePointer {
+            file_id: self.head_file_id,
+            offset: self.head_offset,
+            len: entry.len() as u64,
+        };
+        self.head_offset += entry.len() as u64;
+        Ok(pointer)
+    }
+
+    /// 根据指针读取 value
+    pub fn read(&self, pointer: &ValuePointer) -> Result<ByteVec> {
+        Ok(read_entry(&self.dir, pointer)?.value)
+    }
+
+    /// 顺序读取 vLog 文件中的所有 entry，以及指向它们的指针
+    ///
+    /// 文件尾部不完整的 entry 将被忽略
+    pub fn read_file_entries(&self, file_id: u64) -> Result<Vec<(ValuePointer, VlogEntry)>> {
+        let content = read(vlog_file_path(&self.dir, file_id))?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + VLOG_ENTRY_HEADER_SIZE <= content.len() {
+            let key_len = u64::from_le_bytes(content[offset + 4..offset + 12].try_into()?);
+            let value_len = u64::from_le_bytes(content[offset + 12..offset + 20].try_into()?);
+            let len = VLOG_ENTRY_HEADER_SIZE + key_len as usize + value_len as usize;
+            if offset + len > content.len() {
+                break;
+            }
+            let entry = VlogEntry::decode(&content[offset..offset + len])?;
+            let pointer = ValuePointer {
+                file_id,
+                offset: offset as u64,
+                len: len as u64,
+            };
+            entries.push((pointer, entry));
+            offset += len;
+        }
+        Ok(entries)
+    }
+
+    /// 删除整个 vLog 文件
+    pub fn remove_file(&self, file_id: u64) -> Result<()> {
+        remove_file(vlog_file_path(&self.dir, file_id))?;
+        sync_dir(&self.dir)
+    }
+
+    /// 将当前写入的文件 fsync 到磁盘
+    pub fn sync(&mut self) -> Result<()> {
+        self.head_writer.flush()?;
+        self.head_writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// 当前写入文件的 fsync 句柄，切换文件之后同样指向新的文件
+    pub fn sync_handle(&self) -> Arc<Mutex<File>> {
+        self.sync_file.clone()
+    }
+
+    /// 切换新的文件写入
+    pub fn rotate(&mut self) -> Result<()> {
+        self.sync()?;
+        self.head_file_id = gen_file_number();
+        let file = open_option_default(vlog_file_path(&self.dir, self.head_file_id))?;
+        sync_dir(&self.dir)?;
+        *self.sync_file.lock().unwrap() = file.try_clone()?;
+        self.head_writer = BufWriter::new(file);
+        self.head_offset = 0;
+        self.rotated = true;
+        Ok(())
+    }
+}
+
+/// 根据指针读取一条完整的 entry
+pub fn read_entry(dir: &Path, pointer: &ValuePointer) -> Result<VlogEntry> {
+    let mut file = File::open(vlog_file_path(dir, pointer.file_id))?;
+    file.seek(SeekFrom::Start(pointer.offset))?;
+    let mut content = vec![0_u8; pointer.len as usize];
+    file.read_exact(&mut content)?;
+    VlogEntry::decode(&content)
+}
+
+/// vLog 目录：data_dir/vlog_dir
+pub fn vlog_dir(data_dir: &Path) -> Result<PathBuf> {
+    let dir = data_dir.join(&SERVER_CONFIG.vlog_dir);
+    create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// vLog 文件的 path
+pub fn vlog_file_path(dir: &Path, file_id: u64) -> PathBuf {
+    get_file_path(dir, file_id as i64, SERVER_CONFIG.vlog_file_suffix.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn append_and_read_test() -> Result<()> {
+        let data_dir = env::temp_dir().join("r_wisckey_vlog_test");
+        let _ = remove_dir_all(&data_dir);
+        let mut vlog = ValueLog::open(&data_dir)?;
+        let value = vec![0xff_u8; SERVER_CONFIG.value_threshold];
+        assert!(ValueLog::should_separate(&value));
+
+        let pointer = vlog.append(b"vlog_test_key", &value)?;
+        assert_eq!(ValuePointer::decode(&pointer.encode())?, pointer);
+        assert_eq!(vlog.read(&pointer)?, value);
+
+        let entry = read_entry(&vlog.dir, &pointer)?;
+        assert_eq!(entry.key, b"vlog_test_key");
+
+        remove_dir_all(&data_dir)?;
+        Ok(())
+    }
+}
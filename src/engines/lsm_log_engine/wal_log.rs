@@ -3,26 +3,103 @@
 #![allow(dead_code)]
 
 use anyhow::Result;
-use log::{error, info};
+use log::{info, warn};
 use serde_derive::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::BTreeMap;
-use std::env;
-use std::fs::{create_dir_all, read_dir, File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::{PathBuf};
-use std::sync::{Arc, Mutex};
-
-use crate::common::fn_util::{checksum, checksum_verify, gen_sequence, open_option_default};
+use std::fs::{create_dir_all, read, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::common::error_enum::WiscError;
+use crate::common::fn_util::{
+    checksum, gen_file_number, gen_sequence, get_file_path, open_option_default, sorted_gen_list,
+    sync_dir,
+};
 use crate::common::types::ByteVec;
 use crate::config::SERVER_CONFIG;
+use crate::engines::lsm_log_engine::comparator::{
+    internal_key, parse_trailer, user_key, INTERNAL_KEY_TRAILER_SIZE,
+};
 
 /// block 大小：32 KB
 pub const BLOCK_SIZE: usize = 1024 * 32;
 /// checksum (4 bytes), _type(1 bytes), value_len(8 bytes)
 pub const RECORD_HEADER_SIZE: usize = 4 + 1 + 8;
-/// 日志文件达到预定大小（4MB），将转换为 sort table，并创建新的日志文件以供将来更新
-pub const LOG_FILE_MAX_SIZE: u64 = 1024 * 1024 * 4;
+
+/// WAL 的持久化方式
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WalSyncMode {
+    /// 只写入操作系统的 page cache，不主动 fsync
+    None,
+    /// 每次写入之后都 fsync
+    EveryWrite,
+    /// 后台线程每隔 `wal_sync_interval_ms` 毫秒 fsync 一次
+    Interval,
+    /// 每次写入都等待 fsync，同时等待的写入共享同一次 fsync
+    GroupCommit,
+}
+
+/// group commit 的状态
+#[derive(Debug, Default)]
+struct GroupCommitState {
+    /// 已经写入操作系统的最新 ticket
+    appended: u64,
+    /// 已经 fsync 的最新 ticket
+    synced: u64,
+    /// 是否有 leader 正在执行 fsync
+    syncing: bool,
+    /// 实际执行 fsync 的次数
+    syncs: u64,
+}
+
+/// group commit：写入者先领取 ticket，然后等待覆盖该 ticket 的 fsync
+///
+/// 没有 fsync 正在执行时，等待者成为 leader，一次 fsync 覆盖所有已经领取的 ticket；
+/// 其他等待者阻塞在 condvar 上，由 leader 完成之后唤醒
+#[derive(Debug, Default)]
+pub struct GroupCommit {
+    state: Mutex<GroupCommitState>,
+    cond: Condvar,
+}
+impl GroupCommit {
+    /// 数据写入操作系统之后领取 ticket
+    pub fn append(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.appended += 1;
+        state.appended
+    }
+
+    /// 等待 ticket 之前写入的数据全部 fsync 到磁盘
+    pub fn sync(&self, ticket: u64, file: &Mutex<File>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.synced < ticket {
+            if state.syncing {
+                state = self.cond.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let target = state.appended;
+            drop(state);
+            let result = file.lock().unwrap().sync_data();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = target;
+                state.syncs += 1;
+            }
+            self.cond.notify_all();
+            result?;
+        }
+        Ok(())
+    }
+
+    /// 实际执行 fsync 的次数
+    pub fn syncs(&self) -> u64 {
+        self.state.lock().unwrap().syncs
+    }
+}
 
 /// WAL日志写入的引用结构
 #[derive(Debug)]
@@ -31,57 +108,145 @@ pub struct LogRecordWrite {
     block_writer: BufWriter<File>,
     /// 当前 log 文件的写path
     block_writer_file: Arc<Mutex<PathBuf>>,
+    /// 当前 log 文件的另一个句柄，用于在不持有写句柄的情况下 fsync
+    sync_file: Arc<Mutex<File>>,
+    /// 持久化方式
+    sync_mode: WalSyncMode,
+    group_commit: Arc<GroupCommit>,
+    /// log 文件所在的目录
+    log_dir: PathBuf,
     /// 上一次add_process的RecordType
     last_record_type: RecordType,
     /// 当前block剩余的空间，初始化就是满的 BLOCK_SIZE
     block_writer_rest_len: usize,
 }
 impl LogRecordWrite {
-    /// 初始化 LogRecord 实体
-    pub fn new() -> Result<Self> {
+    /// 初始化 LogRecord 实体，log 文件创建在 log_dir 中
+    pub fn new(log_dir: &Path) -> Result<Self> {
         // 当前 log 文件的写句柄
-        let (block_writer, path) = gen_block_writer()?;
+        let (block_writer, path) = gen_block_writer(log_dir)?;
         info!("{:?}",&path);
+        let sync_file = Arc::new(Mutex::new(block_writer.get_ref().try_clone()?));
         Ok(LogRecordWrite {
             block_writer,
             block_writer_file: Arc::new(Mutex::new(path)),
+            sync_file,
+            sync_mode: SERVER_CONFIG.wal_sync_mode,
+            group_commit: Arc::new(GroupCommit::default()),
+            log_dir: log_dir.to_path_buf(),
             last_record_type: RecordType::None,
             block_writer_rest_len: BLOCK_SIZE,
         })
     }
 
+    /// log 文件所在的目录
+    pub fn log_dir(&self) -> &Path {
+        &self.log_dir
+    }
+
     /// 获取当前写日志文件的path
     pub fn write_log_path(&self) -> Arc<Mutex<PathBuf>> {
         self.block_writer_file.clone()
     }
 
+    /// 当前 log 文件的 fsync 句柄，切换文件之后同样指向新的文件
+    pub fn sync_handle(&self) -> Arc<Mutex<File>> {
+        self.sync_file.clone()
+    }
+
+    pub fn group_commit(&self) -> Arc<GroupCommit> {
+        self.group_commit.clone()
+    }
+
+    /// 调整持久化方式，之后的写入生效
+    pub fn set_sync_mode(&mut self, sync_mode: WalSyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+    /// 将当前 log 文件 fsync 到磁盘
+    pub fn sync(&mut self) -> Result<()> {
+        self.block_writer.flush()?;
+        self.block_writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// 切换到新的 log 文件，返回写满的旧文件的 path
+    ///
+    /// 与内存表的切换同步进行，每个 log 文件中的数据都属于同一个内存表，
+    /// 该内存表 flush 之后旧文件即可删除
+    pub fn rotate(&mut self) -> Result<PathBuf> {
+        // 旧文件中的数据需要在 minor compaction 完成之前保持可恢复
+        if self.sync_mode != WalSyncMode::None {
+            self.sync()?;
+        }
+        let (_, old_path) = self.switch_file()?;
+        Ok(old_path)
+    }
+
+    /// 写入失败之后切换到新的 log 文件，丢弃缓冲区中残留的数据
+    ///
+    /// 写入失败的 record 已经向调用方返回了错误，残留的数据不能再写入旧文件，否则重放时这条 record 会重新生效；
+    /// 旧文件末尾不完整的 record 在重放时按照 `wal_recovery_mode` 处理
+    pub fn reset(&mut self) -> Result<PathBuf> {
+        let (old_writer, old_path) = self.switch_file()?;
+        // into_parts 不会 flush 缓冲区
+        let (_, discarded) = old_writer.into_parts();
+        if let Ok(discarded) = discarded {
+            warn!(
+                "丢弃 log 文件 {:?} 中未写入的 {} 字节",
+                old_path,
+                discarded.len()
+            );
+        }
+        Ok(old_path)
+    }
+
+    /// 创建新的 log 文件并切换写句柄，返回旧的写句柄和旧文件的 path
+    fn switch_file(&mut self) -> Result<(BufWriter<File>, PathBuf)> {
+        let (writer, path) = gen_block_writer(&self.log_dir)?;
+        *self.sync_file.lock().unwrap() = writer.get_ref().try_clone()?;
+        let old_writer = std::mem::replace(&mut self.block_writer, writer);
+        // 新的文件从一个完整的 block 开始，读取时按照文件起始位置划分 block
+        self.block_writer_rest_len = BLOCK_SIZE;
+        self.last_record_type = RecordType::None;
+        let old_path = std::mem::replace(&mut *self.block_writer_file.lock().unwrap(), path);
+        log::info!("{:?}", &old_path);
+        log::info!("{:?}", &self.block_writer_file);
+        Ok((old_writer, old_path))
+    }
+
     /// 往 log 中添加 record
     ///
     /// 调用该方法之前初始化 Key，这里只负责写入
-    ///
-    /// return : 是否切换了新的 log ；engine 需要此信息去更改 memtable
-    pub fn add_records(&mut self, data: &Key) -> Result<Option<PathBuf>> {
-        let mut new_path = Option::None;
-        // 当前log 文件大小校验,超过大小，创建新的log 文件写入
-        if self.block_writer.get_ref().metadata()?.len() >= LOG_FILE_MAX_SIZE {
-            // 注意返回去的是 上一个满了的path ，而不是新的path，新的path将会在下次
-            {
-                new_path = Some(self.block_writer_file.lock().unwrap().clone());
-            }
+    pub fn add_records(&mut self, data: &Key) -> Result<()> {
+        self.add_batch(std::slice::from_ref(data))
+    }
 
-            let (writer, path) = gen_block_writer()?;
-            self.block_writer = writer;
-            {
-                *self.block_writer_file.lock().unwrap() = path;
-            }
-            log::info!("{:?}",&new_path);
-            log::info!("{:?}",&self.block_writer_file);
+    /// 将一组 Key 作为一条逻辑 record 写入 log，并按照 `sync_mode` 等待 fsync
+    pub fn add_batch(&mut self, keys: &[Key]) -> Result<()> {
+        if let Some(ticket) = self.append_batch(keys)? {
+            self.group_commit.sync(ticket, &self.sync_file)?;
         }
+        Ok(())
+    }
 
-        let mut data_byte = data.encode();
-        // info!("data:{:?}",data);
+    /// 将一组 Key 作为一条逻辑 record 写入 log，超过 block 的部分按照 First/Middle/Last 分段
+    ///
+    /// 重放时整条 record 要么完整恢复，要么整体丢弃。所有分段写完之后 flush 一次，
+    /// `every_write` 方式下立即 fsync；`group_commit` 方式下只领取 ticket 并返回，
+    /// 调用方释放写锁之后再通过 `GroupCommit::sync` 等待，同时等待的写入才能共享同一次 fsync
+    pub fn append_batch(&mut self, keys: &[Key]) -> Result<Option<u64>> {
+        let mut data_byte = encode_batch(keys);
         self.add_process(&mut data_byte)?;
-        Ok(new_path)
+        self.block_writer.flush()?;
+        Ok(match self.sync_mode {
+            WalSyncMode::EveryWrite => {
+                self.block_writer.get_ref().sync_data()?;
+                None
+            }
+            WalSyncMode::GroupCommit => Some(self.group_commit.append()),
+            WalSyncMode::None | WalSyncMode::Interval => None,
+        })
     }
     /// 单独的处理流程。分离方便递归调用
     fn add_process(&mut self, data_byte: &mut ByteVec) -> Result<()> {
@@ -133,6 +298,8 @@ impl LogRecordWrite {
                 self.block_writer.flush()?;
                 self.block_writer_rest_len = BLOCK_SIZE;
                 info!("当前record 空header");
+                // 在新的 block 中继续写入这条 record
+                return self.add_process(data_byte);
             }
             Ordering::Less => {
                 // 使用 [0_u8;block_free_size] 填充
@@ -141,22 +308,22 @@ impl LogRecordWrite {
                 self.block_writer.flush()?;
                 self.block_writer_rest_len = BLOCK_SIZE;
                 info!("当前record [0_u8;block_free_size] 填充");
+                return self.add_process(data_byte);
             }
         }
         Ok(())
     }
     /// 写 指定type的record；并flush 和更新 block_writer_rest_len
     fn write_for_type(&mut self, data_byte: &mut ByteVec, _type: RecordType) -> Result<()> {
-        let checksum = checksum(data_byte.as_slice());
+        let checksum = RecordHeader::checksum_of(_type.clone() as u8, data_byte.as_slice());
         let record_header =
             RecordHeader::new(checksum, _type.clone() as u8, data_byte.len() as u64);
 
         let mut header_byte = bincode::serialize(&record_header)?;
         header_byte.append(data_byte);
 
+        // 所有分段写完之后在 add_records 中统一 flush
         self.block_writer.write_all(header_byte.as_slice())?;
-        // 每写一条record就flush
-        self.block_writer.flush()?;
         // 注意，不能直接重置为 BLOCK_SIZE，因为它可能是不满 block的
         self.block_writer_rest_len -= header_byte.len();
         // 如果为 0 ，重置为满 block，重新开始写
@@ -170,161 +337,338 @@ impl LogRecordWrite {
 }
 
 /// 获取一个新的log 文件写句柄 和他的path
-fn gen_block_writer() -> Result<(BufWriter<File>, PathBuf)> {
-    let log_dir = env::current_dir()?.join(&SERVER_CONFIG.wal_dir);
-    create_dir_all(log_dir.clone())?;
+fn gen_block_writer(log_dir: &Path) -> Result<(BufWriter<File>, PathBuf)> {
+    create_dir_all(log_dir)?;
 
-    let file_name = format!("{}.{}", gen_sequence(), SERVER_CONFIG.log_file_extension);
+    let file_name = format!("{}.{}", gen_file_number(), SERVER_CONFIG.log_file_extension);
     let path = log_dir.join(file_name.as_str());
     let log_file = open_option_default(path.clone())?;
+    // 新文件的目录项同样需要持久化，否则崩溃之后整个文件可能丢失
+    sync_dir(log_dir)?;
     // 当前 log 文件的写句柄
     Ok((BufWriter::with_capacity(BLOCK_SIZE, log_file), path))
 }
 
+/// 根据 log 文件的 path 获取它的编号
+pub fn log_number(path: &Path) -> Option<u64> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse::<u64>().ok())
+}
+
+/// log 目录中所有的 log 文件，按照编号从旧到新排列
+pub fn log_files(log_dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    create_dir_all(log_dir)?;
+    Ok(sorted_gen_list(
+        log_dir,
+        SERVER_CONFIG.log_file_extension.as_str(),
+        SERVER_CONFIG.log_file_suffix.as_str(),
+    )?
+    .into_iter()
+    .map(|number| {
+        let path = get_file_path(
+            log_dir,
+            number as i64,
+            SERVER_CONFIG.log_file_suffix.as_str(),
+        );
+        (number, path)
+    })
+    .collect())
+}
+
+/// WAL 重放时遇到损坏或者不完整的 record 的处理方式，参考 LevelDB/RocksDB 的 WALRecoveryMode
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WalRecoveryMode {
+    /// 只容忍文件尾部不完整或损坏的 record（写入时崩溃），其他位置的损坏导致启动失败
+    TolerateCorruptedTailRecords,
+    /// 任何损坏或者不完整的 record 都导致启动失败
+    AbsoluteConsistency,
+    /// 在第一个损坏的 record 处停止，丢弃它之后的所有数据（包括之后的 log 文件）
+    PointInTimeRecovery,
+    /// 跳过所有损坏的 record，恢复其余的数据
+    SkipAnyCorruptedRecords,
+}
+
+/// 重放时被丢弃的字节范围 [start, end)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DroppedRange {
+    /// log 文件编号
+    pub log_number: u64,
+    pub start: u64,
+    pub end: u64,
+    pub reason: String,
+}
+
+/// 解析 log 文件得到的一项：一条完整的 record 中的所有 `Key`，或者被丢弃的字节范围
+#[derive(Debug)]
+enum ParsedRecord {
+    Batch(Vec<Key>),
+    Dropped(DroppedRange),
+}
+
 /// WAL日志读取的引用结构
 ///
-/// wal 文件始终只存在一个，服务器运行的过程中，
-/// 服务器启动的时候log 文件夹中也只有一个，
-/// 运行过程中创建新文件之前要先删除旧log 文件
+/// 每个 reader 读取一个 log 文件，启动时按照编号顺序读取所有尚未持久化的 log 文件
 #[derive(Debug)]
 pub struct LogRecordRead {
-    /// 当前 log 文件的读柄
-    block_reader: Option<BufReader<File>>,
-    /// value 值的字节数组，将会在读的过程中累积
-    ///
-    /// 得到完整的 value 之后将会清空该字节数组（服务于跨block读取）
-    value_byte: ByteVec,
-    /// 已读长度
-    have_read_len: u64,
-    /// data 容器
-    recovery_data: BTreeMap<String, Key>,
+    /// log 文件的路径
+    log_path: PathBuf,
+    /// 按照写入顺序读取到的 `Key`
+    recovery_data: Vec<Key>,
+    /// 被丢弃的字节范围
+    dropped: Vec<DroppedRange>,
 }
 impl LogRecordRead {
-    pub fn new() -> Result<Self> {
-        let log_dir = env::current_dir()?.join(&SERVER_CONFIG.wal_dir);
-        create_dir_all(log_dir.clone())?;
-        let mut block_reader = None;
-
-        for entry in read_dir(log_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                block_reader = Some(BufReader::new(OpenOptions::new().read(true).open(path)?));
-            }
+    /// 打开指定的 log 文件
+    pub fn new(log_path: &Path) -> Result<Self> {
+        if !log_path.is_file() {
+            return Err(anyhow::Error::from(WiscError::FileNotFound(
+                log_path.to_string_lossy().to_string(),
+            )));
         }
-
         Ok(LogRecordRead {
-            block_reader,
-            value_byte: ByteVec::new(),
-            have_read_len: 0,
-            recovery_data: BTreeMap::new(),
+            log_path: log_path.to_path_buf(),
+            recovery_data: Vec::new(),
+            dropped: Vec::new(),
         })
     }
 
-    /// 读取整个log 文件
-    #[allow(unused)]
-    pub fn read_log(&mut self) -> Result<()> {
-        if let Some(reader) = self.block_reader.as_mut() {
-            while self.have_read_len < reader.get_ref().metadata()?.len() {
-                LogRecordRead::read_block_process(
-                    reader,
-                    &mut self.have_read_len,
-                    &mut self.recovery_data,
-                    &mut self.value_byte,
-                )?;
+    /// 按照 mode 读取整个log 文件
+    ///
+    /// mode 不允许出现的损坏返回 `WiscError::WalCorrupted`，其余被丢弃的范围可以通过 `dropped` 获取
+    pub fn read_log(&mut self, mode: WalRecoveryMode) -> Result<()> {
+        let content = read(&self.log_path)?;
+        let log_number = log_number(&self.log_path).unwrap_or(0);
+        let records = parse_records(&content, log_number);
+
+        let first_dropped = records
+            .iter()
+            .position(|record| matches!(record, ParsedRecord::Dropped(_)));
+        let corrupted = |range: &DroppedRange| {
+            anyhow::Error::from(WiscError::WalCorrupted(format!(
+                "{:?} [{}, {}) {}",
+                self.log_path, range.start, range.end, range.reason
+            )))
+        };
+        let keep = match (mode, first_dropped) {
+            (_, None) => records.len(),
+            (WalRecoveryMode::AbsoluteConsistency, Some(index)) => {
+                if let ParsedRecord::Dropped(range) = &records[index] {
+                    return Err(corrupted(range));
+                }
+                unreachable!()
+            }
+            (WalRecoveryMode::TolerateCorruptedTailRecords, Some(index)) => {
+                // 损坏之后仍然有完整的 record，说明损坏不在尾部
+                if records[index..]
+                    .iter()
+                    .any(|record| matches!(record, ParsedRecord::Batch(_)))
+                {
+                    if let ParsedRecord::Dropped(range) = &records[index] {
+                        return Err(corrupted(range));
+                    }
+                }
+                records.len()
+            }
+            (WalRecoveryMode::PointInTimeRecovery, Some(index)) => {
+                if let ParsedRecord::Dropped(range) = &records[index] {
+                    self.dropped.push(DroppedRange {
+                        end: content.len() as u64,
+                        reason: format!("{}，丢弃之后的所有数据", range.reason),
+                        ..range.clone()
+                    });
+                }
+                index
             }
-            info!("读取完毕：have_read_len {}", self.have_read_len);
+            (WalRecoveryMode::SkipAnyCorruptedRecords, Some(_)) => records.len(),
         };
+
+        for record in records.into_iter().take(keep) {
+            match record {
+                ParsedRecord::Batch(keys) => {
+                    self.recovery_data.extend(keys);
+                }
+                ParsedRecord::Dropped(range) => self.dropped.push(range),
+            }
+        }
+        for range in self.dropped.iter() {
+            warn!("丢弃 log 文件 {:?} 中的数据: {:?}", self.log_path, range);
+        }
+        info!(
+            "读取完毕：{:?} {} 条记录",
+            self.log_path,
+            self.recovery_data.len()
+        );
         Ok(())
     }
 
-    /// 处理一个block的数据
-    fn read_block_process(
-        block_reader: &mut BufReader<File>,
-        have_read_len: &mut u64,
-        recovery_data: &mut BTreeMap<String, Key>,
-        value_byte: &mut ByteVec,
-    ) -> Result<()> {
-        let mut buffer = [0; BLOCK_SIZE];
-        // 自增 已读取 的长度
-        *have_read_len += block_reader.read(&mut buffer)? as u64;
-        info!("############### 读取block ###############");
-        let mut buffer = ByteVec::from(buffer);
-
-        LogRecordRead::read_record_process(&mut buffer, recovery_data, value_byte)
-    }
-
-    /// 处理一条record
-    fn read_record_process(
-        buffer: &mut ByteVec,
-        recovery_data: &mut BTreeMap<String, Key>,
-        value_byte: &mut ByteVec,
-    ) -> Result<()> {
-        if buffer.len() < RECORD_HEADER_SIZE {
-            return Ok(());
+    /// 被丢弃的字节范围
+    pub fn dropped(&self) -> &[DroppedRange] {
+        &self.dropped
+    }
+
+    /// 读取到的所有 `Key`，按照写入的顺序排列
+    pub fn into_keys(self) -> Vec<Key> {
+        self.recovery_data
+    }
+}
+
+/// 按照 block 解析 log 文件中所有的 record，分段的 record 会被拼接为完整的 `Key`
+///
+/// 以下情况会产生被丢弃的范围：header 或数据超出文件末尾（写入时崩溃）、
+/// 数据超出 block、checksum 不一致、未知的 type、分段不连续以及 `Key` 解码失败
+fn parse_records(content: &[u8], log_number: u64) -> Vec<ParsedRecord> {
+    let mut records = Vec::new();
+    let file_len = content.len();
+    // 正在拼接的分段 record：(起始位置, 已经拼接的数据)
+    let mut pending: Option<(usize, ByteVec)> = None;
+    let dropped = |start: usize, end: usize, reason: &str| {
+        ParsedRecord::Dropped(DroppedRange {
+            log_number,
+            start: start as u64,
+            end: end as u64,
+            reason: reason.to_string(),
+        })
+    };
+
+    let mut pos = 0;
+    while pos < file_len {
+        let block_end = (pos / BLOCK_SIZE + 1) * BLOCK_SIZE;
+        // block 尾部不足一个 header 的填充
+        if block_end - pos < RECORD_HEADER_SIZE {
+            pos = block_end;
+            continue;
         }
-        // 先读取header信息
-        let mut rest_data = buffer.split_off(RECORD_HEADER_SIZE);
-        let header = bincode::deserialize::<RecordHeader>(buffer.as_slice())?;
-        info!("header：{:?}", header);
-
-        // block空 header 的尾部，此次 block 读取完毕
-        if header._type == RecordType::None as u8 {
-            Ok(())
+        if pos + RECORD_HEADER_SIZE > file_len {
+            let start = pending.take().map_or(pos, |(start, _)| start);
+            records.push(dropped(start, file_len, "header 不完整"));
+            break;
         }
-        // 读取 header 中data_len 的数据即可得到数据
-        else if header._type == RecordType::Full as u8 {
-            let mut other_data = rest_data.split_off(header.value_len as usize);
-            if !checksum_verify(rest_data.as_slice(), header.checksum) {
-                error!("checksum 校验失败: {:?}", &header);
-            } else {
-                let key_test = Key::decode(&mut rest_data)?;
-                recovery_data.insert(key_test.get_sort_key(), key_test);
-            }
-            // 继续执行
-            LogRecordRead::read_record_process(&mut other_data, recovery_data, value_byte)?;
-            Ok(())
-        } else if header._type == RecordType::First as u8 {
-            // 对于first 来说它不需要知道value ，只需要把剩下的直接拼接到value_byte 即可
-            if !checksum_verify(rest_data.as_slice(), header.checksum) {
-                error!("checksum 校验失败: {:?}", &header);
-            } else {
-                value_byte.append(&mut rest_data);
+        let header_end = pos + RECORD_HEADER_SIZE;
+        let header = match bincode::deserialize::<RecordHeader>(&content[pos..header_end]) {
+            Ok(header) => header,
+            Err(_) => {
+                records.extend(take_pending(&mut pending, pos, &dropped));
+                records.push(dropped(pos, block_end.min(file_len), "header 解析失败"));
+                pos = block_end;
+                continue;
             }
-            Ok(())
+        };
+        // block 尾部恰好只能存放一个空 header
+        if header == RecordHeader::default() && header_end == block_end {
+            pos = block_end;
+            continue;
         }
-        // 往value_byte 中填充数据
-        else if header._type == RecordType::Middle as u8 {
-            // 对于Middle 来说同样它不需要知道value ，只需要把剩下的直接拼接到value_byte 即可
-            if !checksum_verify(rest_data.as_slice(), header.checksum) {
-                error!("checksum 校验失败: {:?}", &header);
-            } else {
-                value_byte.append(&mut rest_data);
-            }
-            Ok(())
+        let data_end = header_end.saturating_add(header.value_len as usize);
+        if data_end > block_end {
+            // 数据不会跨越 block，超出说明 header 损坏，丢弃整个 block 剩余的部分
+            records.extend(take_pending(&mut pending, pos, &dropped));
+            records.push(dropped(
+                pos,
+                block_end.min(file_len),
+                "record 长度超出 block",
+            ));
+            pos = block_end;
+            continue;
         }
-        // RecordType::LastType 的情况
-        else {
-            // 往value_byte 中填充数据,并且之后可以获取完整的value
-            // 对于last 来说 它后面可能还会有数据，所以它需要value_size 来确定截取的长度
-            let mut other_data = rest_data.split_off(header.value_len as usize);
-            if !checksum_verify(rest_data.as_slice(), header.checksum) {
-                error!("checksum 校验失败: {:?}", &header);
+        if data_end > file_len {
+            let start = pending.take().map_or(pos, |(start, _)| start);
+            records.push(dropped(start, file_len, "record 不完整"));
+            break;
+        }
+        let data = &content[header_end..data_end];
+        if RecordHeader::checksum_of(header._type, data) != header.checksum {
+            // value_len 可能已经损坏，无法确定下一条 record 的位置，丢弃整个 block 剩余的部分
+            records.extend(take_pending(&mut pending, pos, &dropped));
+            records.push(dropped(pos, block_end.min(file_len), "checksum 校验失败"));
+            pos = block_end;
+            continue;
+        }
+
+        let record_type = header._type;
+        if record_type == RecordType::Full as u8 || record_type == RecordType::First as u8 {
+            records.extend(take_pending(&mut pending, pos, &dropped));
+            if record_type == RecordType::Full as u8 {
+                records.push(decode_record(data.to_vec(), pos, data_end, &dropped));
             } else {
-                value_byte.append(&mut rest_data);
-                let key_test = Key::decode(value_byte)?;
-                recovery_data.insert(key_test.get_sort_key(), key_test);
-                // 清空 value_byte
-                value_byte.clear();
+                pending = Some((pos, data.to_vec()));
+            }
+        } else if record_type == RecordType::Middle as u8 || record_type == RecordType::Last as u8 {
+            match pending.as_mut() {
+                Some((_, value_byte)) => value_byte.extend_from_slice(data),
+                None => records.push(dropped(pos, data_end, "分段 record 缺少 First")),
+            }
+            if record_type == RecordType::Last as u8 {
+                if let Some((start, value_byte)) = pending.take() {
+                    records.push(decode_record(value_byte, start, data_end, &dropped));
+                }
             }
-            // 继续执行
-            info!("last 读取完毕");
-            LogRecordRead::read_record_process(&mut other_data, recovery_data, value_byte)?;
-            Ok(())
+        } else {
+            records.extend(take_pending(&mut pending, pos, &dropped));
+            records.push(dropped(pos, data_end, "未知的 record type"));
         }
+        pos = data_end;
+    }
+    records.extend(take_pending(&mut pending, file_len, &dropped));
+    records
+}
+
+/// 丢弃正在拼接的分段 record，它的范围截止到 end
+fn take_pending(
+    pending: &mut Option<(usize, ByteVec)>,
+    end: usize,
+    dropped: &impl Fn(usize, usize, &str) -> ParsedRecord,
+) -> Option<ParsedRecord> {
+    pending
+        .take()
+        .map(|(start, _)| dropped(start, end, "分段 record 不完整"))
+}
+
+/// 解码一条完整的 record
+fn decode_record(
+    value_byte: ByteVec,
+    start: usize,
+    end: usize,
+    dropped: &impl Fn(usize, usize, &str) -> ParsedRecord,
+) -> ParsedRecord {
+    match decode_batch(&value_byte) {
+        Ok(keys) => ParsedRecord::Batch(keys),
+        Err(_) => dropped(start, end, "Key 解码失败"),
     }
 }
 
+/// 一条逻辑 record 的内容：`count(4) | (len(4) | Key)*`
+pub fn encode_batch(keys: &[Key]) -> ByteVec {
+    let mut buf = ByteVec::new();
+    buf.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+    for key in keys {
+        let mut key_byte = key.encode();
+        buf.extend_from_slice(&(key_byte.len() as u32).to_le_bytes());
+        buf.append(&mut key_byte);
+    }
+    buf
+}
+
+pub fn decode_batch(content: &[u8]) -> Result<Vec<Key>> {
+    let invalid = || anyhow::Error::from(WiscError::WalCorrupted("batch 格式错误".to_string()));
+    let count = u32::from_le_bytes(content.get(0..4).ok_or_else(invalid)?.try_into()?);
+    let mut keys = Vec::with_capacity(count as usize);
+    let mut offset = 4;
+    for _ in 0..count {
+        let len_byte = content.get(offset..offset + 4).ok_or_else(invalid)?;
+        let len = u32::from_le_bytes(len_byte.try_into()?) as usize;
+        offset += 4;
+        let key_byte = content.get(offset..offset + len).ok_or_else(invalid)?;
+        keys.push(Key::decode(&mut key_byte.to_vec())?);
+        offset += len;
+    }
+    if offset != content.len() {
+        return Err(invalid());
+    }
+    Ok(keys)
+}
+
 /// header 结构布局
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct RecordHeader {
@@ -340,6 +684,15 @@ impl RecordHeader {
             _type,
         }
     }
+
+    /// checksum 同时覆盖 header 中的 _type、value_len 以及数据，header 损坏也可以被发现
+    pub fn checksum_of(_type: u8, data: &[u8]) -> u32 {
+        let mut content = ByteVec::with_capacity(1 + 8 + data.len());
+        content.push(_type);
+        content.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        content.extend_from_slice(data);
+        checksum(&content)
+    }
 }
 impl Default for RecordHeader {
     fn default() -> Self {
@@ -373,24 +726,34 @@ pub enum CommandType {
     Delete,
 }
 
-/// internal_key = key + sequence + type
+/// internal_key = key + !(sequence << 8 | type)，格式见 `comparator` 模块
 ///
 /// Key = internal_key_size + internal_key + value_size + value
 #[derive(Debug, Clone)]
 pub struct Key {
     internal_key_size: u64,
-    key: String,
+    key: ByteVec,
     sequence: i64,
     data_type: u8,
     value_size: u64,
-    value: String,
+    value: ByteVec,
 }
 impl Key {
-    pub fn new(key: String, value: String, data_type: DataType) -> Self {
-        let sequence = gen_sequence(); // 8
+    pub fn new(key: impl Into<ByteVec>, value: impl Into<ByteVec>, data_type: DataType) -> Self {
+        Key::with_sequence(key, value, data_type, gen_sequence())
+    }
+
+    /// 使用指定的 sequence 创建，用于 batch 中连续的 sequence
+    pub fn with_sequence(
+        key: impl Into<ByteVec>,
+        value: impl Into<ByteVec>,
+        data_type: DataType,
+        sequence: i64,
+    ) -> Self {
+        let (key, value) = (key.into(), value.into());
         let data_type = data_type as u8; // 1
-        let value_size = value.as_bytes().len() as u64; // 8
-        let internal_key_size = key.as_bytes().len() as u64 + 9_u64;
+        let value_size = value.len() as u64; // 8
+        let internal_key_size = (key.len() + INTERNAL_KEY_TRAILER_SIZE) as u64;
         Key {
             internal_key_size,
             key,
@@ -401,39 +764,81 @@ impl Key {
         }
     }
 
-    /// 从 Key实例中 获取用于排序的key。
-    pub fn get_sort_key(&self) -> String {
-        format!("{}-{}", self.key, self.sequence)
+    /// 编码之后的 internal key，用于排序
+    pub fn internal_key(&self) -> ByteVec {
+        internal_key(&self.key, self.sequence, self.data_type)
+    }
+
+    /// 用户 key
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// 用户 value
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    pub fn sequence(&self) -> i64 {
+        self.sequence
+    }
+
+    /// 写入时在写锁中重新分配 sequence
+    pub fn set_sequence(&mut self, sequence: i64) {
+        self.sequence = sequence;
+    }
+
+    /// value 是否是指向 vLog 的指针
+    pub fn is_value_pointer(&self) -> bool {
+        self.data_type == DataType::ValuePointer as u8
+    }
+
+    /// 编码之后的字节长度
+    pub fn encoded_len(&self) -> usize {
+        8 + self.internal_key_size as usize + 8 + self.value_size as usize
+    }
+
+    /// 是否是删除标记（墓碑）
+    pub fn is_deleted(&self) -> bool {
+        self.data_type == DataType::Delete as u8
     }
 
     pub fn encode(&self) -> ByteVec {
         let mut buf = ByteVec::new();
 
         buf.append(&mut self.internal_key_size.to_le_bytes().to_vec());
-        buf.append(&mut self.key.as_bytes().to_vec());
-        buf.append(&mut self.sequence.to_le_bytes().to_vec());
-        buf.append(&mut self.data_type.to_le_bytes().to_vec());
+        buf.append(&mut self.internal_key());
         buf.append(&mut self.value_size.to_le_bytes().to_vec());
-        buf.append(&mut self.value.as_bytes().to_vec());
+        buf.extend_from_slice(&self.value);
 
         buf.clone()
     }
 
     pub fn decode(content: &mut ByteVec) -> Result<Self> {
+        if content.len() < 8 {
+            return Err(anyhow::Error::from(WiscError::WalCorrupted(format!(
+                "key len {}",
+                content.len()
+            ))));
+        }
         let mut rest_content = content.split_off(8_usize);
         let internal_key_size = bincode::deserialize::<u64>(content.as_slice())?;
 
-        // 切割出 key + sequence + data_type
+        // 切割出 internal_key
+        if (internal_key_size as usize) < INTERNAL_KEY_TRAILER_SIZE
+            || internal_key_size as usize + 8 > rest_content.len()
+        {
+            return Err(anyhow::Error::from(WiscError::WalCorrupted(format!(
+                "internal key size {}",
+                internal_key_size
+            ))));
+        }
         let mut value_content = rest_content.split_off(internal_key_size as usize);
-        let key_rest_content = rest_content.split_off(rest_content.len() - 9_usize);
-        let key = String::from_utf8(rest_content)?;
-        let (sequence_byte, data_type_byte) = key_rest_content.split_at(8_usize);
-        let sequence = bincode::deserialize::<i64>(sequence_byte)?;
-        let data_type = bincode::deserialize::<u8>(data_type_byte)?;
+        let (sequence, data_type) = parse_trailer(&rest_content);
+        let key = user_key(&rest_content).to_vec();
 
-        let value_byte = value_content.split_off(8_usize);
+        let value = value_content.split_off(8_usize);
         let value_size = bincode::deserialize::<u64>(value_content.as_slice())?;
-        let value = String::from_utf8(value_byte)?;
         Ok(Key {
             internal_key_size,
             key,
@@ -452,19 +857,27 @@ impl Key {
 pub enum DataType {
     Delete,
     Set,
+    /// value 存放在 vLog 中，`Key` 中的 value 为 `ValuePointer`
+    ValuePointer,
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::common::fn_util::log_init;
+    use std::env;
+    use std::fs::{remove_dir_all, OpenOptions};
     use std::io::Read;
 
+    fn log_dir() -> PathBuf {
+        env::current_dir().unwrap().join(&SERVER_CONFIG.wal_dir)
+    }
+
     #[test]
     fn add_records_01_test() -> Result<()> {
         log_init();
         // 垮block 数据 测试
-        let mut log_record = LogRecordWrite::new()?;
+        let mut log_record = LogRecordWrite::new(&log_dir())?;
         let mut str = String::new();
         let _ = File::open("a.txt")?.read_to_string(&mut str);
         let key_test = Key::new("a".to_string(), str, DataType::Set);
@@ -476,7 +889,7 @@ mod test {
     fn add_records_02_test() -> Result<()> {
         log_init();
         // 跨block 和正常 数据 测试
-        let mut log_record = LogRecordWrite::new()?;
+        let mut log_record = LogRecordWrite::new(&log_dir())?;
         let key_test = Key::new("b".to_string(), "bb".to_string(), DataType::Set);
         log_record.add_records(&key_test)?;
 
@@ -495,7 +908,7 @@ mod test {
     #[test]
     fn add_records_03_test() -> Result<()> {
         log_init();
-        let mut log_record = LogRecordWrite::new()?;
+        let mut log_record = LogRecordWrite::new(&log_dir())?;
         let data = vec![
             ("a".to_string(), "bb".to_string()),
             ("a".to_string(), "bb".to_string()),
@@ -516,7 +929,7 @@ mod test {
     #[test]
     fn add_records_04_test() -> Result<()> {
         log_init();
-        let mut log_record = LogRecordWrite::new()?;
+        let mut log_record = LogRecordWrite::new(&log_dir())?;
         let data = vec![("测试".to_string(), "测试".to_string())];
         data.iter().for_each(|(key, value)| {
             let key_test = Key::new(key.clone(), value.clone(), DataType::Set);
@@ -527,14 +940,238 @@ mod test {
 
     #[test]
     fn read_test() -> Result<()> {
-        log_init();
-        let mut reader = LogRecordRead::new()?;
-        reader.read_log()?;
-        let data = reader.recovery_data;
-        println!("{:?}", data);
+        let log_dir = env::temp_dir().join("r_wisckey_wal_read_test");
+        let _ = remove_dir_all(&log_dir);
+        let mut log_record = LogRecordWrite::new(&log_dir)?;
+        // 跨 block 的数据
+        let big_value = "v".repeat(BLOCK_SIZE * 2);
+        log_record.add_records(&Key::new("a".to_string(), "aa".to_string(), DataType::Set))?;
+        log_record.add_records(&Key::new("b".to_string(), big_value.clone(), DataType::Set))?;
+        log_record.add_records(&Key::new("c".to_string(), "".to_string(), DataType::Delete))?;
+        log_record.sync()?;
+
+        let files = log_files(&log_dir)?;
+        assert_eq!(files.len(), 1);
+        let mut reader = LogRecordRead::new(&files[0].1)?;
+        reader.read_log(WalRecoveryMode::AbsoluteConsistency)?;
+        assert!(reader.dropped().is_empty());
+        let keys = reader.into_keys();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[1].value(), big_value.as_bytes());
+        assert!(keys[2].is_deleted());
+
+        remove_dir_all(&log_dir)?;
         Ok(())
     }
 
+    #[test]
+    fn rotate_test() -> Result<()> {
+        let log_dir = env::temp_dir().join("r_wisckey_wal_rotate_test");
+        let _ = remove_dir_all(&log_dir);
+        let mut log_record = LogRecordWrite::new(&log_dir)?;
+        log_record.add_records(&Key::new("a".to_string(), "aa".to_string(), DataType::Set))?;
+        let old_path = log_record.rotate()?;
+        log_record.add_records(&Key::new("b".to_string(), "bb".to_string(), DataType::Set))?;
+        log_record.sync()?;
+
+        // 切换之后的写入只出现在新的文件中
+        let files = log_files(&log_dir)?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].1, old_path);
+        assert_eq!(*log_record.write_log_path().lock().unwrap(), files[1].1);
+        let (keys, _) = read_with(&old_path, WalRecoveryMode::AbsoluteConsistency)?;
+        assert_eq!(keys, vec!["a"]);
+        let (keys, _) = read_with(&files[1].1, WalRecoveryMode::AbsoluteConsistency)?;
+        assert_eq!(keys, vec!["b"]);
+
+        remove_dir_all(&log_dir)?;
+        Ok(())
+    }
+
+    /// 字节范围 [start, end)
+    type Ranges = Vec<(u64, u64)>;
+
+    /// 写入 record，返回 log 文件以及每条 record 的 [start, end)
+    fn write_records(name: &str, values: &[(&str, usize)]) -> Result<(PathBuf, Ranges)> {
+        let log_dir = env::temp_dir().join(name);
+        let _ = remove_dir_all(&log_dir);
+        let mut log_record = LogRecordWrite::new(&log_dir)?;
+        let path = log_record.write_log_path().lock().unwrap().clone();
+        let mut ranges = Vec::new();
+        for (key, value_len) in values {
+            let start = path.metadata()?.len();
+            let key = Key::new(key.to_string(), key.repeat(*value_len), DataType::Set);
+            log_record.add_records(&key)?;
+            log_record.sync()?;
+            ranges.push((start, path.metadata()?.len()));
+        }
+        Ok((path, ranges))
+    }
+
+    fn read_with(path: &Path, mode: WalRecoveryMode) -> Result<(Vec<String>, Ranges)> {
+        let mut reader = LogRecordRead::new(path)?;
+        reader.read_log(mode)?;
+        let dropped = reader
+            .dropped()
+            .iter()
+            .map(|range| (range.start, range.end))
+            .collect();
+        let keys = reader
+            .into_keys()
+            .iter()
+            .map(|key| String::from_utf8_lossy(key.key()).to_string())
+            .collect();
+        Ok((keys, dropped))
+    }
+
+    #[test]
+    fn block_padding_test() -> Result<()> {
+        // 长度不同的 record 会让 block 末尾剩余各种大小的空间，包括不足一个 header 的情况
+        let log_dir = env::temp_dir().join("r_wisckey_wal_block_padding_test");
+        let _ = remove_dir_all(&log_dir);
+        let mut log_record = LogRecordWrite::new(&log_dir)?;
+        let path = log_record.write_log_path().lock().unwrap().clone();
+        let count = 20_000;
+        for i in 0..count {
+            let key = Key::new(format!("key-{:05}", i), "v".repeat(i % 37), DataType::Set);
+            log_record.add_records(&key)?;
+        }
+        log_record.sync()?;
+        assert!(path.metadata()?.len() > 4 * BLOCK_SIZE as u64);
+
+        let (keys, dropped) = read_with(&path, WalRecoveryMode::AbsoluteConsistency)?;
+        assert!(dropped.is_empty());
+        assert_eq!(keys.len(), count);
+        let expected: Vec<_> = (0..count).map(|i| format!("key-{:05}", i)).collect();
+        assert_eq!(keys, expected);
+
+        remove_dir_all(&log_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn group_commit_test() -> Result<()> {
+        let log_dir = env::temp_dir().join("r_wisckey_wal_group_commit_test");
+        let _ = remove_dir_all(&log_dir);
+        let log_record = LogRecordWrite::new(&log_dir)?;
+        let group_commit = log_record.group_commit();
+        let file = log_record.sync_handle();
+        let threads = 8;
+        let barrier = Arc::new(std::sync::Barrier::new(threads));
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let (group_commit, file, barrier) =
+                    (group_commit.clone(), file.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    let ticket = group_commit.append();
+                    // 所有写入者都领取 ticket 之后再等待 fsync
+                    barrier.wait();
+                    group_commit.sync(ticket, &file)
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(group_commit.syncs(), 1);
+
+        remove_dir_all(&log_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn torn_tail_test() -> Result<()> {
+        let (path, ranges) = write_records(
+            "r_wisckey_wal_torn_tail_test",
+            &[("a", 10), ("b", 10), ("c", 10)],
+        )?;
+        // 最后一条 record 只写入了一部分
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(ranges[2].1 - 3)?;
+
+        let (keys, dropped) = read_with(&path, WalRecoveryMode::TolerateCorruptedTailRecords)?;
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(dropped, vec![(ranges[2].0, ranges[2].1 - 3)]);
+        assert!(read_with(&path, WalRecoveryMode::AbsoluteConsistency).is_err());
+
+        remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn torn_batch_test() -> Result<()> {
+        let log_dir = env::temp_dir().join("r_wisckey_wal_torn_batch_test");
+        let _ = remove_dir_all(&log_dir);
+        let mut log_record = LogRecordWrite::new(&log_dir)?;
+        let path = log_record.write_log_path().lock().unwrap().clone();
+        log_record.add_records(&Key::new("a".to_string(), "aa".to_string(), DataType::Set))?;
+        // 跨越多个 block 的 batch
+        let batch = [
+            Key::new("b".to_string(), "b".repeat(BLOCK_SIZE), DataType::Set),
+            Key::new("c".to_string(), "cc".to_string(), DataType::Set),
+            Key::new("a".to_string(), "".to_string(), DataType::Delete),
+        ];
+        log_record.add_batch(&batch)?;
+        log_record.sync()?;
+
+        let (keys, _) = read_with(&path, WalRecoveryMode::AbsoluteConsistency)?;
+        assert_eq!(keys, vec!["a", "b", "c", "a"]);
+
+        // batch 的最后一个分段丢失，整个 batch 都不能生效
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(path.metadata()?.len() - 10)?;
+        let (keys, dropped) = read_with(&path, WalRecoveryMode::TolerateCorruptedTailRecords)?;
+        assert_eq!(keys, vec!["a"]);
+        assert_eq!(dropped.len(), 1);
+
+        remove_dir_all(&log_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_middle_test() -> Result<()> {
+        // c 跨越两个 block，d 位于第二个 block 中
+        let (path, ranges) = write_records(
+            "r_wisckey_wal_corrupted_middle_test",
+            &[("a", 10), ("b", 10), ("c", BLOCK_SIZE), ("d", 10)],
+        )?;
+        // 破坏 b 的 header 中的 value_len，checksum 同样可以发现
+        let mut content = read(&path)?;
+        content[ranges[1].0 as usize + 5] ^= 0x01;
+        std::fs::write(&path, &content)?;
+
+        assert!(read_with(&path, WalRecoveryMode::TolerateCorruptedTailRecords).is_err());
+        assert!(read_with(&path, WalRecoveryMode::AbsoluteConsistency).is_err());
+
+        let (keys, dropped) = read_with(&path, WalRecoveryMode::PointInTimeRecovery)?;
+        assert_eq!(keys, vec!["a"]);
+        assert_eq!(dropped, vec![(ranges[1].0, ranges[3].1)]);
+
+        // header 损坏之后无法确定 record 的边界，丢弃 block 剩余的部分，c 的 Last 分段随之被丢弃
+        let (keys, dropped) = read_with(&path, WalRecoveryMode::SkipAnyCorruptedRecords)?;
+        assert_eq!(keys, vec!["a", "d"]);
+        let block_size = BLOCK_SIZE as u64;
+        assert_eq!(
+            dropped,
+            vec![(ranges[1].0, block_size), (block_size, ranges[2].1)]
+        );
+
+        remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn decode_truncated_key_test() {
+        let encoded = Key::with_sequence("a", "aa", DataType::Set, 1).encode();
+        for len in [0, 7, 8, encoded.len() - 10] {
+            let err = Key::decode(&mut encoded[..len].to_vec()).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<WiscError>(),
+                Some(WiscError::WalCorrupted(_))
+            ));
+        }
+    }
+
     #[test]
     fn test() {
 
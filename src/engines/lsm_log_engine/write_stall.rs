@@ -0,0 +1,174 @@
+//! 写入限流
+//!
+//! 后台的 flush 和 compaction 跟不上写入速度时，分阶段地限制写入：
+//!
+//! 1. level-0 的文件数达到 `LEVEL_0_SLOWDOWN_TRIGGER` 时，每次写入延迟 1ms，把 CPU 让给 compaction
+//! 2. level-0 的文件数达到 `LEVEL_0_STOP_TRIGGER`，或者 mut_table 写满时不可变内存表的个数已经达到
+//!    `max_immutable_memtables`，写入阻塞在条件变量上
+//! 3. flush 或 compaction 提交之后唤醒所有阻塞的写入，由它们重新检查是否可以继续
+//!
+//! 后台任务失败之后调用 `stop`，阻塞的写入全部返回，由存储引擎拒绝之后的写入，`resume` 之后恢复
+//!
+//! 每个阶段的次数和时长记录在 `StallStatistics` 中
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// slowdown 阶段每次写入的延迟
+pub const SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// 写入被阻塞的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallCause {
+    /// level-0 文件数达到上限，停止写入
+    Level0Stop,
+    /// 不可变内存表个数达到上限，mut_table 无法切换，停止写入
+    MemtableFull,
+}
+
+/// 写入限流的统计信息，时长单位为微秒
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StallStatistics {
+    pub level_0_slowdown_count: u64,
+    pub level_0_slowdown_micros: u64,
+    pub level_0_stop_count: u64,
+    pub level_0_stop_micros: u64,
+    pub memtable_stop_count: u64,
+    pub memtable_stop_micros: u64,
+}
+
+#[derive(Debug, Default)]
+struct StallCounter {
+    count: AtomicU64,
+    micros: AtomicU64,
+}
+impl StallCounter {
+    fn record(&self, start: Instant) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> (u64, u64) {
+        (
+            self.count.load(Ordering::Relaxed),
+            self.micros.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// 写入线程和后台的 flush / compaction 线程共享的限流状态
+#[derive(Debug, Default)]
+pub struct WriteStall {
+    /// 条件变量使用的锁，阻塞条件本身由调用方检查
+    lock: Mutex<()>,
+    changed: Condvar,
+    /// 为 true 时不再阻塞任何写入
+    stopped: AtomicBool,
+    level_0_slowdown: StallCounter,
+    level_0_stop: StallCounter,
+    memtable_stop: StallCounter,
+}
+impl WriteStall {
+    fn counter(&self, cause: StallCause) -> &StallCounter {
+        match cause {
+            StallCause::Level0Stop => &self.level_0_stop,
+            StallCause::MemtableFull => &self.memtable_stop,
+        }
+    }
+
+    /// slowdown 阶段：延迟本次写入
+    pub fn delay(&self) {
+        let start = Instant::now();
+        thread::sleep(SLOWDOWN_DELAY);
+        self.level_0_slowdown.record(start);
+    }
+
+    /// stop 阶段：阻塞直到 `blocked` 返回 false 或者调用了 `stop`，不需要阻塞时立即返回且不计入统计
+    ///
+    /// `blocked` 在持有内部锁时调用，不能在其中调用 `notify`。
+    /// 后台线程总是先修改状态、再调用 `notify`，因此不会错过唤醒
+    pub fn wait_while(&self, cause: StallCause, mut blocked: impl FnMut() -> bool) {
+        let guard = self.lock.lock().unwrap();
+        if self.is_stopped() || !blocked() {
+            return;
+        }
+        let start = Instant::now();
+        let _guard = self
+            .changed
+            .wait_while(guard, |_| !self.is_stopped() && blocked())
+            .unwrap();
+        self.counter(cause).record(start);
+    }
+
+    /// 后台任务失败时调用，唤醒所有阻塞的写入，之后也不再阻塞
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.notify();
+    }
+
+    /// 错误恢复之后重新开始限流
+    pub fn resume(&self) {
+        self.stopped.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// flush 或 compaction 提交之后调用，唤醒所有阻塞的写入重新检查
+    pub fn notify(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    pub fn statistics(&self) -> StallStatistics {
+        let (level_0_slowdown_count, level_0_slowdown_micros) = self.level_0_slowdown.load();
+        let (level_0_stop_count, level_0_stop_micros) = self.level_0_stop.load();
+        let (memtable_stop_count, memtable_stop_micros) = self.memtable_stop.load();
+        StallStatistics {
+            level_0_slowdown_count,
+            level_0_slowdown_micros,
+            level_0_stop_count,
+            level_0_stop_micros,
+            memtable_stop_count,
+            memtable_stop_micros,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn wait_and_notify_test() {
+        let stall = Arc::new(WriteStall::default());
+        let flushed = Arc::new(AtomicBool::new(false));
+        // 不需要阻塞时不计入统计
+        stall.wait_while(StallCause::MemtableFull, || false);
+        assert_eq!(stall.statistics(), StallStatistics::default());
+
+        let handle = {
+            let stall = stall.clone();
+            let flushed = flushed.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                flushed.store(true, Ordering::SeqCst);
+                stall.notify();
+            })
+        };
+        stall.wait_while(StallCause::MemtableFull, || !flushed.load(Ordering::SeqCst));
+        handle.join().unwrap();
+        let statistics = stall.statistics();
+        assert_eq!(statistics.memtable_stop_count, 1);
+        assert!(statistics.memtable_stop_micros >= 10_000);
+
+        stall.delay();
+        assert_eq!(stall.statistics().level_0_slowdown_count, 1);
+    }
+}
@@ -1,27 +1,188 @@
 use serde_derive::{Deserialize, Serialize};
-use std::ops::Range;
+use std::ops::Bound;
+
+use crate::common::types::ByteVec;
+use crate::engines::lsm_log_engine::comparator::Comparator;
 
 pub use lsm_log_engine::lsm_engine::LsmLogEngine;
+pub use transaction::Transaction;
 pub mod lsm_log_engine;
+pub mod transaction;
 
+/// 存储引擎的接口
+///
+/// key 和 value 都是任意的字节序列；字符串版本的 set / get / scan / remove 只是在字节接口之上的便捷封装，
+/// 读取到的数据不是合法的 UTF-8 时返回错误
 pub trait KvsEngine {
-    /// 设置字符串键值对
+    /// 设置键值对
     ///
     /// 如果key 已经存在，则之前的对应的value将被新的覆盖
-    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()>;
+    fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()>;
 
     /// 根据key 获取一个 value
     ///
     /// 如果 key 不存在返回 none
-    fn get(&self, key: &str) -> anyhow::Result<Option<String>>;
+    fn get_bytes(&self, key: &[u8]) -> anyhow::Result<Option<ByteVec>>;
 
-    fn scan(&self, range: Scans) -> anyhow::Result<Option<Vec<String>>>;
+    /// 按照 key 的顺序返回范围内所有存活的键值对
+    ///
+    /// 每个 key 只返回最新的版本，已删除的 key 不会返回
+    fn scan_bytes(&self, range: Scans) -> anyhow::Result<Vec<(ByteVec, ByteVec)>>;
 
     /// 删除给定的 key
     ///
-    /// 如果给定的key 不存在将返回 `KvsError::KeyNotFound`
-    fn remove(&mut self, key: &str) -> anyhow::Result<()>;
+    /// 如果给定的key 不存在将返回 `WiscError::KeyNotExist`
+    fn remove_bytes(&mut self, key: &[u8]) -> anyhow::Result<()>;
+
+    /// 手动触发 value log 垃圾回收
+    ///
+    /// 返回回收的字节数
+    fn gc(&mut self) -> anyhow::Result<u64>;
+
+    /// 存储引擎的统计信息，以可读的文本返回
+    fn stats(&self) -> anyhow::Result<String>;
+
+    /// 运行时调整 block 缓存的容量（字节数），缩小时立即淘汰超出的数据
+    fn set_block_cache_capacity(&self, capacity: usize) -> anyhow::Result<()>;
+
+    /// 存储引擎当前的状态，以可读的文本返回
+    ///
+    /// 后台错误之后为只读或者失败状态，同时返回错误的原因
+    fn state(&self) -> String;
+
+    /// 修复后台错误的原因之后恢复写入
+    ///
+    /// 没有错误时什么也不做；数据损坏等不能恢复的错误返回 `WiscError::EngineFailed`
+    fn resume(&mut self) -> anyhow::Result<()>;
+
+    /// 原子地写入一批 put 和 delete
+    ///
+    /// 整个 batch 作为一条 WAL record 写入，崩溃恢复时要么全部生效，要么全部丢弃
+    fn write_batch(&mut self, batch: WriteBatch) -> anyhow::Result<()>;
+
+    /// 获取 key 的 value 以及最新版本的 sequence
+    ///
+    /// 最新版本是删除标记时 value 为 none，不存在任何版本时 sequence 为 0；
+    /// 事务根据 sequence 判断 key 是否被修改过
+    fn get_with_sequence(&self, key: &[u8]) -> anyhow::Result<(Option<ByteVec>, i64)>;
+
+    /// 开始一个乐观事务
+    fn begin(&self) -> Transaction {
+        Transaction::new()
+    }
+
+    /// 提交事务
+    ///
+    /// 事务读取过的 key 在读取之后被修改时返回 `WiscError::TransactionConflict`，不写入任何数据；
+    /// 否则事务中的所有写入作为一个 batch 原子地写入
+    fn commit(&mut self, transaction: Transaction) -> anyhow::Result<()> {
+        transaction.validate(self)?;
+        self.write_batch(transaction.into_batch())
+    }
+
+    /// 设置字符串键值对
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// 根据字符串 key 获取字符串 value
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(match self.get_bytes(key.as_bytes())? {
+            Some(value) => Some(String::from_utf8(value)?),
+            None => None,
+        })
+    }
+
+    /// 字符串版本的范围查询
+    fn scan(&self, range: Scans) -> anyhow::Result<Vec<(String, String)>> {
+        self.scan_bytes(range)?
+            .into_iter()
+            .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+            .collect()
+    }
+
+    /// 删除字符串 key
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
+
+/// `WriteBatch` 中的一个操作
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Put(ByteVec, ByteVec),
+    Delete(ByteVec),
+}
+
+/// 一组需要原子写入的操作，按照添加的顺序生效
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
 }
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.ops
+            .push(BatchOp::Put(key.as_ref().to_vec(), value.as_ref().to_vec()));
+        self
+    }
+
+    /// 删除不检查 key 是否存在，直接写入删除标记
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.ops.push(BatchOp::Delete(key.as_ref().to_vec()));
+        self
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Scans(Range<String>);
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// 范围查询的参数
+///
+/// 起止边界可以是包含、不包含或者无边界，limit 限制返回的最大条数
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scans {
+    pub start: Bound<ByteVec>,
+    pub end: Bound<ByteVec>,
+    pub limit: Option<usize>,
+}
+impl Scans {
+    pub fn new(start: Bound<ByteVec>, end: Bound<ByteVec>, limit: Option<usize>) -> Self {
+        Scans { start, end, limit }
+    }
+
+    /// key 是否在范围内，按照 comparator 的顺序比较
+    pub fn contains(&self, comparator: &dyn Comparator, key: &[u8]) -> bool {
+        self.after_start(comparator, key) && self.before_end(comparator, key)
+    }
+
+    /// key 是否满足起始边界
+    pub fn after_start(&self, comparator: &dyn Comparator, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => comparator.compare(key, start).is_ge(),
+            Bound::Excluded(start) => comparator.compare(key, start).is_gt(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// key 是否满足结束边界
+    pub fn before_end(&self, comparator: &dyn Comparator, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => comparator.compare(key, end).is_le(),
+            Bound::Excluded(end) => comparator.compare(key, end).is_lt(),
+            Bound::Unbounded => true,
+        }
+    }
+}
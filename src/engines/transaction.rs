@@ -0,0 +1,120 @@
+//! 乐观事务
+//!
+//! 事务中的写入先缓存在本地，读取时优先读取自己的写入，并记录读到的每个 key 最新版本的 sequence。
+//! 提交时检查这些 key 的最新版本是否发生了变化，没有变化才将所有写入作为一个 `WriteBatch` 原子地写入
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+use crate::common::error_enum::WiscError;
+use crate::common::types::ByteVec;
+use crate::engines::{KvsEngine, WriteBatch};
+
+/// 由 `KvsEngine::begin` 创建，通过 `KvsEngine::commit` 提交，直接 drop 即回滚
+#[derive(Debug, Default)]
+pub struct Transaction {
+    /// 按照顺序缓存的写入
+    batch: WriteBatch,
+    /// 每个 key 最后一次写入的 value，`None` 表示删除，用于读取自己的写入
+    writes: BTreeMap<ByteVec, Option<ByteVec>>,
+    /// 读取过的 key 以及读取时最新版本的 sequence，不存在任何版本时为 0
+    reads: BTreeMap<ByteVec, i64>,
+}
+impl Transaction {
+    pub fn new() -> Self {
+        Transaction::default()
+    }
+
+    /// 读取 key，事务中写入过的 key 直接返回写入的值
+    pub fn get<E: KvsEngine + ?Sized>(
+        &mut self,
+        engine: &E,
+        key: &[u8],
+    ) -> Result<Option<ByteVec>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let (value, sequence) = engine.get_with_sequence(key)?;
+        // 同一个 key 以第一次读取的版本为准，之后再读到不同的版本说明已经冲突
+        self.reads.entry(key.to_vec()).or_insert(sequence);
+        Ok(value)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.batch.put(key, value);
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.batch.delete(key);
+        self.writes.insert(key.to_vec(), None);
+        self
+    }
+
+    /// 检查读取过的 key 是否被其他写入修改，有修改时返回 `WiscError::TransactionConflict`
+    ///
+    /// 调用方需要保证检查和写入之间没有其他的写入
+    pub fn validate<E: KvsEngine + ?Sized>(&self, engine: &E) -> Result<()> {
+        for (key, sequence) in &self.reads {
+            if engine.get_with_sequence(key)?.1 != *sequence {
+                return Err(anyhow::Error::from(WiscError::TransactionConflict(
+                    String::from_utf8_lossy(key).to_string(),
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// 事务中缓存的所有写入
+    pub fn into_batch(self) -> WriteBatch {
+        self.batch
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engines::LsmLogEngine;
+    use std::env;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn transaction_test() -> Result<()> {
+        let root = env::temp_dir().join("r_wisckey").join("transaction_test");
+        let _ = remove_dir_all(&root);
+        let mut engine = LsmLogEngine::open_at(&root)?;
+        engine.set("txn_a", "v1")?;
+
+        let mut txn = engine.begin();
+        assert_eq!(txn.get(&engine, b"txn_a")?, Some(b"v1".to_vec()));
+        txn.put(b"txn_a", b"v2").delete(b"txn_b");
+        // 读取自己的写入，提交之前其他读取看不到
+        assert_eq!(txn.get(&engine, b"txn_a")?, Some(b"v2".to_vec()));
+        assert_eq!(txn.get(&engine, b"txn_b")?, None);
+        assert_eq!(engine.get("txn_a")?, Some("v1".to_string()));
+        engine.commit(txn)?;
+        assert_eq!(engine.get("txn_a")?, Some("v2".to_string()));
+
+        // 读取之后 key 被修改，提交失败并且不写入任何数据
+        let mut txn = engine.begin();
+        assert_eq!(txn.get(&engine, b"txn_a")?, Some(b"v2".to_vec()));
+        assert_eq!(txn.get(&engine, b"txn_c")?, None);
+        txn.put(b"txn_d", b"v1");
+        engine.set("txn_c", "v1")?;
+        let err = engine.commit(txn).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WiscError>(),
+            Some(WiscError::TransactionConflict(key)) if key == "txn_c"
+        ));
+        assert_eq!(engine.get("txn_d")?, None);
+
+        // 只写不读的事务不会冲突
+        let mut txn = engine.begin();
+        txn.put(b"txn_a", b"v3");
+        engine.set("txn_a", "v4")?;
+        engine.commit(txn)?;
+        assert_eq!(engine.get("txn_a")?, Some("v3".to_string()));
+        Ok(())
+    }
+}
@@ -4,6 +4,14 @@ pub mod config;
 mod engines;
 mod server;
 
-pub use client::Client;
-pub use engines::{KvsEngine, LsmLogEngine};
+pub use client::{Client, Command};
+pub use engines::lsm_log_engine::comparator::{BytewiseComparator, Comparator};
+pub use engines::lsm_log_engine::compression::CompressionType;
+pub use engines::lsm_log_engine::error_handler::{
+    BackgroundError, EngineState, ErrorSeverity, ErrorSource,
+};
+pub use engines::lsm_log_engine::scheduler::ShutdownMode;
+pub use engines::lsm_log_engine::snapshot::Snapshot;
+pub use engines::lsm_log_engine::sstable::{dump_tables, TableProperties};
+pub use engines::{BatchOp, KvsEngine, LsmLogEngine, Scans, Transaction, WriteBatch};
 pub use server::Server;
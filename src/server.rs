@@ -2,19 +2,26 @@
 
 use crate::client::Command;
 use crate::common::error_enum::WiscError;
+use crate::common::types::{ByteVec, DisplayBytes};
+use crate::engines::{BatchOp, Transaction};
 use crate::KvsEngine;
 use anyhow::Result;
-use log::{error, info};
+use log::{error, info, warn};
 use std::io::{BufReader, BufWriter};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
 
 /// 服务实例
 pub struct Server<E: KvsEngine> {
     engine: E,
+    /// 当前连接中正在进行的事务，连接断开时放弃
+    transaction: Option<Transaction>,
 }
 impl<E: KvsEngine> Server<E> {
     pub fn new(engine: E) -> Self {
-        Server { engine }
+        Server {
+            engine,
+            transaction: None,
+        }
     }
 
     /// 在给定的地址上启动server 监听
@@ -28,6 +35,9 @@ impl<E: KvsEngine> Server<E> {
                         // 处理流
                         if let Err(e) = self.process(stream) {
                             error!("Error on serving client: {:?}", e);
+                            if self.transaction.take().is_some() {
+                                warn!("连接断开，放弃未提交的事务");
+                            }
                             break;
                         }
                     }
@@ -45,7 +55,7 @@ impl<E: KvsEngine> Server<E> {
         let req = bincode::deserialize_from::<BufReader<&TcpStream>, Command>(reader)?;
         info!("接收到请求{:?}", &req);
 
-        let result = client_command_process(&req, &mut self.engine);
+        let result = client_command_process(&req, &mut self.engine, &mut self.transaction);
         let writer = BufWriter::new(tcp);
         bincode::serialize_into::<BufWriter<&TcpStream>, String>(writer, &result)?;
 
@@ -54,51 +64,199 @@ impl<E: KvsEngine> Server<E> {
 }
 
 /// 执行 Command
-pub fn client_command_process(command: &Command, engine: &mut dyn KvsEngine) -> String {
+///
+/// `transaction` 不为空时，get / insert / update / delete / batch 在事务中执行，直到 commit 或 rollback；
+/// scan、gc 以及管理命令始终直接作用于存储引擎
+pub fn client_command_process(
+    command: &Command,
+    engine: &mut dyn KvsEngine,
+    transaction: &mut Option<Transaction>,
+) -> String {
     let result = match command {
-        Command::Get(key) => match engine.get(key.as_str()) {
+        Command::Get(key) => match read(engine, transaction, key) {
             Ok(opt) => {
-                format!("{:?}", opt)
+                format!("{:?}", opt.as_deref().map(DisplayBytes))
+            }
+            Err(err) => {
+                format!("{:?}", err)
+            }
+        },
+
+        Command::Scan(range) => match engine.scan_bytes(range.clone()) {
+            Ok(pairs) => {
+                let pairs: Vec<_> = pairs
+                    .iter()
+                    .map(|(key, value)| (DisplayBytes(key), DisplayBytes(value)))
+                    .collect();
+                format!("{:?}", pairs)
             }
             Err(err) => {
                 format!("{:?}", err)
             }
         },
 
-        Command::Delete(key) => match engine.remove(key.as_str()) {
+        Command::Gc => match engine.gc() {
+            Ok(reclaimed_bytes) => {
+                format!("OK, reclaimed {} bytes", reclaimed_bytes)
+            }
+            Err(err) => {
+                format!("{:?}", err)
+            }
+        },
+
+        Command::Stats => match engine.stats() {
+            Ok(stats) => stats,
+            Err(err) => {
+                format!("{:?}", err)
+            }
+        },
+
+        Command::State => engine.state(),
+
+        Command::Resume => match engine.resume() {
             Ok(_) => "OK".to_string(),
             Err(err) => {
                 format!("{:?}", err)
             }
         },
 
-        Command::Insert(key, value) => match engine.get(key.as_str()).unwrap() {
-            Some(_) => {
-                let desc = format!("{:?}", WiscError::KeyExist(key.clone()));
+        Command::BlockCache(capacity) => match engine.set_block_cache_capacity(*capacity) {
+            Ok(_) => "OK".to_string(),
+            Err(err) => {
+                format!("{:?}", err)
+            }
+        },
+
+        Command::Batch(batch) => match transaction {
+            Some(transaction) => {
+                for op in batch.ops() {
+                    match op {
+                        BatchOp::Put(key, value) => transaction.put(key, value),
+                        BatchOp::Delete(key) => transaction.delete(key),
+                    };
+                }
+                "OK".to_string()
+            }
+            None => match engine.write_batch(batch.clone()) {
+                Ok(_) => "OK".to_string(),
+                Err(err) => {
+                    format!("{:?}", err)
+                }
+            },
+        },
+
+        Command::Delete(key) => match transaction {
+            Some(transaction) => match transaction.get(engine, key) {
+                Ok(Some(_)) => {
+                    transaction.delete(key);
+                    "OK".to_string()
+                }
+                Ok(None) => format!(
+                    "{:?}",
+                    WiscError::KeyNotExist(String::from_utf8_lossy(key).to_string())
+                ),
+                Err(err) => {
+                    format!("{:?}", err)
+                }
+            },
+            None => match engine.remove_bytes(key) {
+                Ok(_) => "OK".to_string(),
+                Err(err) => {
+                    format!("{:?}", err)
+                }
+            },
+        },
+
+        Command::Insert(key, value) => match read(engine, transaction, key) {
+            Ok(Some(_)) => {
+                let desc = format!(
+                    "{:?}",
+                    WiscError::KeyExist(String::from_utf8_lossy(key).to_string())
+                );
                 error!("{:?}", &desc);
                 desc
             }
-            None => match engine.set(key.as_str(), value.as_str()) {
+            Ok(None) => match write(engine, transaction, key, value) {
                 Ok(_) => "OK".to_string(),
                 Err(err) => {
                     format!("{:?}", err)
                 }
             },
+            Err(err) => {
+                format!("{:?}", err)
+            }
         },
 
-        Command::Update(key, value) => match engine.get(key.as_str()).unwrap() {
-            Some(_) => match engine.set(key.as_str(), value.as_str()) {
+        Command::Update(key, value) => match read(engine, transaction, key) {
+            Ok(Some(_)) => match write(engine, transaction, key, value) {
                 Ok(_) => "OK".to_string(),
                 Err(err) => {
                     format!("{:?}", err)
                 }
             },
-            None => {
-                let desc = format!("{:?}", WiscError::KeyNotExist(key.clone()));
+            Ok(None) => {
+                let desc = format!(
+                    "{:?}",
+                    WiscError::KeyNotExist(String::from_utf8_lossy(key).to_string())
+                );
                 error!("{:?}", &desc);
                 desc
             }
+            Err(err) => {
+                format!("{:?}", err)
+            }
+        },
+
+        Command::Begin => match transaction {
+            Some(_) => format!("{:?}", WiscError::TransactionInProgress),
+            None => {
+                *transaction = Some(engine.begin());
+                "OK".to_string()
+            }
+        },
+
+        Command::Commit => match transaction.take() {
+            Some(transaction) => match engine.commit(transaction) {
+                Ok(_) => "OK".to_string(),
+                Err(err) => {
+                    format!("{:?}", err)
+                }
+            },
+            None => format!("{:?}", WiscError::TransactionNotStarted),
+        },
+
+        Command::Rollback => match transaction.take() {
+            Some(_) => "OK".to_string(),
+            None => format!("{:?}", WiscError::TransactionNotStarted),
         },
     };
     result
 }
+
+/// 读取 key，事务中通过事务读取
+fn read(
+    engine: &dyn KvsEngine,
+    transaction: &mut Option<Transaction>,
+    key: &[u8],
+) -> Result<Option<ByteVec>> {
+    match transaction {
+        Some(transaction) => transaction.get(engine, key),
+        None => engine.get_bytes(key),
+    }
+}
+
+/// 写入 key，事务中只缓存在事务里
+fn write(
+    engine: &mut dyn KvsEngine,
+    transaction: &mut Option<Transaction>,
+    key: &[u8],
+    value: &[u8],
+) -> Result<()> {
+    match transaction {
+        Some(transaction) => {
+            transaction.put(key, value);
+            Ok(())
+        }
+        None => engine.set_bytes(key, value),
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::Result;
+use r_wisckey::{BytewiseComparator, Comparator, KvsEngine, LsmLogEngine, Scans};
+use std::cmp::Ordering;
+use std::env;
+use std::fs::remove_dir_all;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// 按照数值大小比较十进制数字 key 的比较器
+#[derive(Debug)]
+struct NumericComparator;
+impl Comparator for NumericComparator {
+    fn name(&self) -> &str {
+        "test.NumericComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+    }
+}
+
+#[test]
+fn external_comparator_test() -> Result<()> {
+    let root = env::temp_dir()
+        .join("r_wisckey")
+        .join("external_comparator_test");
+    let _ = remove_dir_all(&root);
+    let all = || Scans::new(Bound::Unbounded, Bound::Unbounded, None);
+    let expected: Vec<_> = ["9", "10", "100"]
+        .iter()
+        .map(|key| (key.to_string(), format!("v{}", key)))
+        .collect();
+    {
+        let mut engine = LsmLogEngine::open_with_comparator(&root, Arc::new(NumericComparator))?;
+        for key in ["100", "9", "10"] {
+            engine.set(key, &format!("v{}", key))?;
+        }
+        assert_eq!(engine.scan(all())?, expected);
+    }
+    // 重新打开时从 WAL 中恢复，依然按照同一个比较器排列
+    let engine = LsmLogEngine::open_with_comparator(&root, Arc::new(NumericComparator))?;
+    assert_eq!(engine.scan(all())?, expected);
+    drop(engine);
+    // 数据目录与创建时的比较器绑定
+    assert!(LsmLogEngine::open_with_comparator(&root, Arc::new(BytewiseComparator)).is_err());
+    Ok(())
+}